@@ -1,4 +1,7 @@
-use std::path::PathBuf;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
 use clap::{Parser, Subcommand};
 
@@ -9,12 +12,106 @@ pub struct Args {
     pub command: Commands,
 }
 #[derive(Subcommand)]
+#[command(rename_all = "snake_case")]
 pub enum Commands {
+    /// Print a bencoded value as JSON.
+    Decode {
+        /// Bencoded value, e.g. `d3:fooi42ee`.
+        #[arg(required_unless_present = "file")]
+        value: Option<String>,
+        /// Read the bencoded value from a file instead.
+        #[arg(long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+    },
+    /// Print what a torrent file contains.
+    Info {
+        /// Path to the torrent file.
+        torrent: PathBuf,
+        /// Print the information as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Ask the tracker for the peers of a torrent.
+    Peers {
+        /// Path to the torrent file.
+        torrent: PathBuf,
+    },
+    /// Ask the trackers for the seeder and leecher counts of torrents without announcing.
+    Scrape {
+        /// Paths to the torrent files.
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+    },
+    /// Handshake with a single peer.
+    Handshake {
+        /// Path to the torrent file.
+        torrent: PathBuf,
+        /// Address of the peer, `<ip>:<port>` or `[<ipv6>]:<port>`.
+        peer: SocketAddr,
+    },
+    /// Run a tracker that serves `/announce` and `/scrape` over HTTP, and optionally over UDP.
+    Tracker {
+        /// Address to listen on.
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: IpAddr,
+        /// Port of the HTTP tracker.
+        #[arg(long, default_value_t = 6969)]
+        port: u16,
+        /// Also serve the UDP tracker protocol on this port.
+        #[arg(long)]
+        udp: Option<u16>,
+        /// Seconds peers should wait between announces.
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+    },
+    /// Download a torrent from its peers.
     Download {
-        /// Path to store the downloaded file.
+        /// Path to store the downloaded file. For multi-file torrents, the directory in which the
+        /// torrent's own directory is created.
+        #[arg(short)]
+        output_file: PathBuf,
+        /// Path to the torrent file.
+        torrent: PathBuf,
+        /// Seconds a peer may stay silent before it is dropped.
+        #[arg(long = "idle-timeout", default_value_t = 180)]
+        idle_timeout: u64,
+    },
+    /// Download a single piece of a torrent and check it against its hash.
+    DownloadPiece {
+        /// Path to store the downloaded piece.
         #[arg(short)]
         output_file: PathBuf,
         /// Path to the torrent file.
         torrent: PathBuf,
+        /// Zero-based index of the piece to download.
+        index: usize,
+        /// Seconds a peer may stay silent before it is dropped.
+        #[arg(long = "idle-timeout", default_value_t = 180)]
+        idle_timeout: u64,
+    },
+    /// Print what a magnet link contains.
+    MagnetParse {
+        /// Magnet link, `magnet:?xt=urn:btih:...`.
+        link: String,
+    },
+    /// Fetch the info dictionary of a magnet link from its peers and print it like `info`.
+    MagnetInfo {
+        /// Magnet link, `magnet:?xt=urn:btih:...`.
+        link: String,
+        /// Print the information as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Fetch the info dictionary of a magnet link from its peers and download the torrent.
+    MagnetDownload {
+        /// Path to store the downloaded file. For multi-file torrents, the directory in which the
+        /// torrent's own directory is created.
+        #[arg(short)]
+        output_file: PathBuf,
+        /// Magnet link, `magnet:?xt=urn:btih:...`.
+        link: String,
+        /// Seconds a peer may stay silent before it is dropped.
+        #[arg(long = "idle-timeout", default_value_t = 180)]
+        idle_timeout: u64,
     },
 }
@@ -0,0 +1,88 @@
+use serde_bencode::value::Value;
+
+/// Converts a bencoded value into JSON. Byte strings that are not valid UTF-8 (like the `pieces`
+/// field of a torrent) are rendered as hex.
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Int(i) => (*i).into(),
+        Value::Bytes(b) => bytes_to_string(b).into(),
+        Value::List(l) => l.iter().map(to_json).collect(),
+        Value::Dict(d) => serde_json::Value::Object(
+            d.iter()
+                .map(|(k, v)| (bytes_to_string(k), to_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn bytes_to_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => hex::encode(bytes),
+    }
+}
+
+/// Returns the raw bytes of the value stored under `key` in the bencoded dictionary `data`,
+/// exactly as they appear in the input.
+pub fn raw_dict_value<'a>(data: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    if data.first() != Some(&b'd') {
+        return None;
+    }
+    let mut pos = 1;
+    while data.get(pos)? != &b'e' {
+        let key_len = value_len(&data[pos..])?;
+        let current_key = byte_string(&data[pos..pos + key_len])?;
+        pos += key_len;
+        let len = value_len(&data[pos..])?;
+        if current_key == key {
+            return Some(&data[pos..pos + len]);
+        }
+        pos += len;
+    }
+    None
+}
+
+/// Length in bytes of the bencoded value at the start of `data`.
+pub fn value_len(data: &[u8]) -> Option<usize> {
+    match data.first()? {
+        b'i' => Some(data.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while data.get(pos)? != &b'e' {
+                pos += value_len(&data[pos..])?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = data.iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&data[..colon]).ok()?.parse().ok()?;
+            let end = colon.checked_add(1)?.checked_add(len)?;
+            (end <= data.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+/// Contents of the bencoded byte string `data`.
+fn byte_string(data: &[u8]) -> Option<&[u8]> {
+    let colon = data.iter().position(|&b| b == b':')?;
+    data.get(colon + 1..)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_dict_value_keeps_unknown_keys() {
+        let info = b"d6:lengthi3e4:name1:a7:privatei1e5:x-newl1:xi2eee";
+        let torrent = [&b"d8:announce3:url4:info"[..], info, b"e"].concat();
+        assert_eq!(raw_dict_value(&torrent, b"info"), Some(&info[..]));
+    }
+
+    #[test]
+    fn raw_dict_value_of_missing_key() {
+        assert_eq!(raw_dict_value(b"d8:announce3:urle", b"info"), None);
+        assert_eq!(raw_dict_value(b"l4:infoe", b"info"), None);
+    }
+}
@@ -0,0 +1,176 @@
+/// Which pieces of a torrent someone has, one bit per piece. The high bit of the first byte is
+/// piece 0, bits past the last piece are always zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BitfieldError {
+    #[error("bitfield has {actual} bytes, expected {expected}")]
+    InvalidLength { expected: usize, actual: usize },
+    #[error("bitfield has bits set past the last piece")]
+    SpareBitsSet,
+}
+
+impl Bitfield {
+    /// A bitfield of `len` pieces, none of which are set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; (len + 7) / 8],
+            len,
+        }
+    }
+
+    /// A bitfield of `len` pieces, all of which are set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self {
+            bytes: vec![0xff; (len + 7) / 8],
+            len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Takes the payload of a Bitfield message as is, with as many pieces as it has bits. Use
+    /// [`Bitfield::validate`] once the number of pieces is known.
+    pub fn from_payload(bytes: Vec<u8>) -> Self {
+        let len = bytes.len() * 8;
+        Self { bytes, len }
+    }
+
+    /// Checks that the bitfield describes exactly `len` pieces: it must be `ceil(len / 8)` bytes
+    /// long and the spare bits at the end must be zero.
+    pub fn validate(self, len: usize) -> Result<Self, BitfieldError> {
+        let expected = (len + 7) / 8;
+        if self.bytes.len() != expected {
+            return Err(BitfieldError::InvalidLength {
+                expected,
+                actual: self.bytes.len(),
+            });
+        }
+        let bitfield = Self {
+            bytes: self.bytes,
+            len,
+        };
+        let mut cleared = bitfield.clone();
+        cleared.clear_spare_bits();
+        if cleared != bitfield {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        Ok(bitfield)
+    }
+
+    /// The bitfield as sent in a Bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Number of pieces the bitfield describes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Marks piece `index` as present. Returns `false` if there is no such piece.
+    pub fn set(&mut self, index: usize) -> bool {
+        if index >= self.len {
+            return false;
+        }
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+        true
+    }
+
+    /// Marks piece `index` as missing.
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Number of pieces present.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Whether every piece is present.
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indices of the pieces present, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.has(index))
+    }
+
+    /// Pieces present in both bitfields.
+    pub fn intersection(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Pieces present in this bitfield but not in `other`.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    /// Applies `op` byte by byte. Pieces `other` does not describe count as missing there.
+    fn combine(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        let mut result = Self {
+            bytes: self
+                .bytes
+                .iter()
+                .enumerate()
+                .map(|(i, &byte)| op(byte, other.bytes.get(i).copied().unwrap_or(0)))
+                .collect(),
+            len: self.len,
+        };
+        result.clear_spare_bits();
+        result
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xff << spare;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_accepts_exact_bitfield() {
+        let bitfield = Bitfield::from_payload(vec![0b1010_0000, 0b1000_0000])
+            .validate(9)
+            .unwrap();
+        assert_eq!(bitfield.len(), 9);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 2, 8]);
+    }
+
+    #[test]
+    fn validate_rejects_wrong_length() {
+        let result = Bitfield::from_payload(vec![0xff]).validate(9);
+        assert!(matches!(
+            result,
+            Err(BitfieldError::InvalidLength {
+                expected: 2,
+                actual: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_spare_bits() {
+        let result = Bitfield::from_payload(vec![0xff, 0b1100_0000]).validate(9);
+        assert!(matches!(result, Err(BitfieldError::SpareBitsSet)));
+    }
+}
@@ -0,0 +1,1117 @@
+use anyhow::Error;
+use futures::{
+    future::{BoxFuture, FutureExt, OptionFuture},
+    sink::SinkExt,
+    stream::StreamExt,
+};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, Notify},
+    task::JoinSet,
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_util::codec::Framed;
+
+use crate::bitfield::{Bitfield, BitfieldError};
+use crate::extension::ExtensionRegistry;
+use crate::peer::{self, Message, MessageFramer, PeerState};
+use crate::pex::{self, ConnectedPeers, PexExtension};
+use crate::picker::{PiecePicker, RarestFirst};
+use crate::storage::Storage;
+use crate::torrent::Info;
+use crate::tracker::{Event, TrackerSession};
+
+pub const BLOCK_SIZE: usize = 1 << 14;
+/// Number of peers we download from at the same time.
+const MAX_PEERS: usize = 50;
+/// A keep-alive is sent once we have not sent anything to a peer for this long.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// How long to wait before asking the trackers again after none of them answered.
+const ANNOUNCE_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long the announces at the end of a download may take, as nothing else waits for them.
+const FINAL_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests kept outstanding with a peer before its rate and latency are known.
+const MIN_REQUESTS: usize = 4;
+/// Limit of outstanding requests for peers that do not announce theirs in `reqq`, the same as the
+/// default of libtorrent.
+const DEFAULT_MAX_REQUESTS: usize = 250;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("peer connection failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("peer closed the connection")]
+    ConnectionClosed,
+    #[error("unexpected message: expected {expected}, got {got:?}")]
+    UnexpectedMessage {
+        expected: &'static str,
+        got: Message,
+    },
+    #[error("piece {index} does not exist")]
+    InvalidPiece { index: usize },
+    #[error(
+        "peer sent a block that was not requested (index {index}, begin {begin}, length {length})"
+    )]
+    InvalidBlock {
+        index: u32,
+        begin: u32,
+        length: usize,
+    },
+    #[error(
+        "hash of piece {index} does not match: expected {}, got {}",
+        hex::encode(expected),
+        hex::encode(actual)
+    )]
+    HashMismatch {
+        index: usize,
+        expected: [u8; 20],
+        actual: [u8; 20],
+    },
+    #[error("invalid bitfield: {0}")]
+    InvalidBitfield(#[from] BitfieldError),
+    #[error("peer stayed silent for too long")]
+    Timeout,
+    #[error("piece {index} cannot be requested while the peer chokes us")]
+    Choked { index: usize },
+    #[error("peer rejected the request for a block of piece {index}")]
+    Rejected { index: usize },
+    #[error("extension failed: {0}")]
+    Extension(Error),
+}
+
+/// Settings shared by every peer connection of a download.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerConfig {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    /// Peers that send nothing at all, not even keep-alives, for this long are dropped.
+    pub idle_timeout: Duration,
+}
+
+/// A block we asked the peer for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BlockRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+/// A piece being downloaded from a peer.
+struct ActivePiece {
+    index: usize,
+    expected: [u8; 20],
+    data: Vec<u8>,
+    /// Blocks yet to be requested.
+    pending: VecDeque<BlockRequest>,
+    /// Blocks requested but not received yet.
+    requested: Vec<BlockRequest>,
+}
+
+/// The blocks received of a piece that was handed back, so that the next peer only has to
+/// download the rest.
+struct PartialPiece {
+    data: Vec<u8>,
+    /// Blocks not received yet.
+    missing: VecDeque<BlockRequest>,
+}
+
+/// What became of a piece the downloader is done with.
+enum Finished {
+    /// Downloaded and verified.
+    Verified(Vec<u8>),
+    /// Given up because the peer does not let us request it anymore.
+    GivenUp(PartialPiece, DownloadError),
+    /// Downloaded, but it does not match its hash.
+    Failed(DownloadError),
+}
+
+type FinishedPiece = (usize, Finished);
+
+/// Sizes the queue of outstanding requests of a peer, so that the peer always has the next block
+/// at hand: it must cover what the peer can send while a request is on its way to it.
+#[derive(Debug, Default)]
+struct Pipeline {
+    /// Download rate in bytes per second, smoothed over the last blocks.
+    rate: f64,
+    /// Shortest time from a request to its block, i.e. the round trip without any queueing.
+    latency: Option<Duration>,
+    last_block: Option<Instant>,
+}
+
+impl Pipeline {
+    /// Accounts for a block of `length` bytes that was requested at `requested_at`.
+    fn on_block(&mut self, length: usize, requested_at: Instant) {
+        let now = Instant::now();
+        let latency = now - requested_at;
+        self.latency = Some(self.latency.map_or(latency, |min| min.min(latency)));
+        // Time in which the peer had nothing requested does not count against its rate.
+        let since = self
+            .last_block
+            .map_or(requested_at, |last| last.max(requested_at));
+        let sample = length as f64 / (now - since).as_secs_f64().max(1e-3);
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            self.rate + (sample - self.rate) / 8.0
+        };
+        self.last_block = Some(now);
+    }
+
+    /// Number of requests to keep outstanding, at most `max`.
+    fn depth(&self, max: usize) -> usize {
+        let latency = self.latency.map_or(0.0, |latency| latency.as_secs_f64());
+        let in_flight = (self.rate * latency / BLOCK_SIZE as f64).ceil() as usize;
+        // One more than needed, so that the queue grows until the rate stops increasing.
+        (in_flight + 1).max(MIN_REQUESTS).min(max)
+    }
+}
+
+/// Downloads pieces from a single peer over an established connection.
+pub struct PieceDownloader {
+    stream: Framed<TcpStream, MessageFramer>,
+    idle_timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    bitfield: Bitfield,
+    /// The pieces we still need, which decide whether we are interested in the peer.
+    wanted: Bitfield,
+    extensions: ExtensionRegistry,
+    /// Whether both sides support the Fast Extension.
+    fast: bool,
+    state: PeerState,
+    /// Requests the peer has neither served nor rejected yet, with the time they were sent.
+    outstanding: HashMap<BlockRequest, Instant>,
+    /// Requests dropped by a choke or cancelled, which the peer may still serve.
+    cancelled: HashSet<BlockRequest>,
+    pipeline: Pipeline,
+    /// Pieces being downloaded, in the order they were started.
+    active: Vec<ActivePiece>,
+    /// Pieces the peer lets us request even while choked.
+    allowed_fast: Bitfield,
+    /// Pieces the peer rejected requests for while not choking us, which it will not serve.
+    rejected: Bitfield,
+}
+
+impl PieceDownloader {
+    /// Handshakes with the peer and goes through the Bitfield -> Interested -> Unchoke exchange,
+    /// after which the peer is ready to serve requests. With the Fast Extension, a peer that keeps
+    /// choking us but allows some pieces to be requested anyway is ready as well. A peer that has
+    /// none of the `wanted` pieces is returned right after its bitfield, without telling it we
+    /// are interested. If the peer supports the extension protocol, the extended handshake for
+    /// `extensions` is sent right after the handshake.
+    pub async fn connect(
+        address: SocketAddr,
+        config: PeerConfig,
+        wanted: Bitfield,
+        extensions: ExtensionRegistry,
+    ) -> Result<Self, DownloadError> {
+        let nr_of_pieces = wanted.len();
+        // Peers that accept the connection but never send a handshake are silent as well.
+        let (stream, handshake) = timeout(
+            config.idle_timeout,
+            peer::connect(address, config.info_hash, config.peer_id),
+        )
+        .await
+        .map_err(|_| DownloadError::Timeout)??;
+        let mut stream = Framed::new(stream, MessageFramer::for_torrent(nr_of_pieces, BLOCK_SIZE));
+        if handshake.supports_extensions() {
+            let extended_handshake = extensions
+                .handshake(address)
+                .map_err(DownloadError::Extension)?;
+            stream.send(extended_handshake).await?;
+        }
+        let mut downloader = Self {
+            stream,
+            idle_timeout: config.idle_timeout,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            bitfield: Bitfield::new(nr_of_pieces),
+            wanted,
+            extensions,
+            fast: handshake.supports_fast(),
+            state: PeerState::new(),
+            outstanding: HashMap::new(),
+            cancelled: HashSet::new(),
+            pipeline: Pipeline::default(),
+            active: Vec::new(),
+            allowed_fast: Bitfield::new(nr_of_pieces),
+            rejected: Bitfield::new(nr_of_pieces),
+        };
+
+        // 1. step: wait for Bitfield message, or one of its Fast Extension replacements.
+        downloader.bitfield = match downloader.recv().await? {
+            Message::Bitfield(bitfield) => bitfield.validate(nr_of_pieces)?,
+            Message::HaveAll if downloader.fast => Bitfield::full(nr_of_pieces),
+            Message::HaveNone if downloader.fast => Bitfield::new(nr_of_pieces),
+            got => {
+                return Err(DownloadError::UnexpectedMessage {
+                    expected: "Bitfield",
+                    got,
+                })
+            }
+        };
+        // 2. step: send Interested message, if the peer has anything we want.
+        downloader.update_interest().await?;
+        // 3. step: wait for the Unchoke message, or for pieces we may request while choked.
+        while downloader.state.am_interested
+            && downloader.state.am_choked
+            && downloader.allowed_fast.count() == 0
+        {
+            downloader.recv().await?;
+        }
+
+        Ok(downloader)
+    }
+
+    /// Receives the next message from the peer and updates what we know about the peer, telling
+    /// it whether we are interested whenever it gets new pieces. Extended messages are handed to
+    /// their extension and answered right away instead of being returned.
+    ///
+    /// Keeps the connection alive while waiting, and fails once the peer has been silent for
+    /// longer than the idle timeout.
+    async fn recv(&mut self) -> Result<Message, DownloadError> {
+        loop {
+            let message = self.next_frame().await?;
+            if let Some(message) = self.process(message).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Waits for the next message from the peer, keeping the connection alive meanwhile. Unlike
+    /// `recv`, no message is lost when this is cancelled, as it returns as soon as one arrives.
+    async fn next_frame(&mut self) -> Result<Message, DownloadError> {
+        loop {
+            let keep_alive_at = self.keep_alive_at();
+            let timeout_at = self.last_received + self.idle_timeout;
+            let message = tokio::select! {
+                biased;
+                message = next_message(&mut self.stream) => message?,
+                _ = sleep_until(keep_alive_at) => {
+                    self.send(Message::KeepAlive).await?;
+                    continue;
+                }
+                _ = sleep_until(timeout_at) => return Err(DownloadError::Timeout),
+            };
+            self.last_received = Instant::now();
+            return Ok(message);
+        }
+    }
+
+    /// Updates what we know about the peer for a message received from it, see `recv`. Returns
+    /// `None` for messages that are taken care of here.
+    async fn process(&mut self, message: Message) -> Result<Option<Message>, DownloadError> {
+        let replies = match &message {
+            Message::Extended { id, payload } => self
+                .extensions
+                .handle(*id, payload)
+                .map_err(DownloadError::Extension)?,
+            _ => Vec::new(),
+        };
+        // Extensions get a chance to send their own messages whenever the peer talks to us.
+        for reply in replies.into_iter().chain(self.extensions.poll()) {
+            self.send(reply).await?;
+        }
+        self.state.received(&message);
+        match &message {
+            Message::KeepAlive | Message::Extended { .. } => return Ok(None),
+            // Without the Fast Extension, choking silently drops every outstanding request.
+            Message::Choke if !self.fast => self
+                .cancelled
+                .extend(self.outstanding.drain().map(|(request, _)| request)),
+            Message::Have { index } => {
+                if !self.bitfield.set(*index as usize) {
+                    return Err(DownloadError::InvalidPiece {
+                        index: *index as usize,
+                    });
+                }
+                self.update_interest().await?;
+            }
+            Message::AllowedFast { index } => {
+                // Pieces that do not exist are of no use anyway.
+                self.allowed_fast.set(*index as usize);
+            }
+            &Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                // A peer that rejects requests without choking us, or for a piece it allows
+                // fast, will not serve the piece.
+                if self.outstanding.remove(&request).is_some()
+                    && (!self.state.am_choked || self.allowed_fast.has(index as usize))
+                {
+                    self.rejected.set(index as usize);
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let request = BlockRequest {
+                    index: *index,
+                    begin: *begin,
+                    length: block.len() as u32,
+                };
+                match self.outstanding.remove(&request) {
+                    Some(requested_at) => self.pipeline.on_block(block.len(), requested_at),
+                    // Sent before the peer noticed that the request is gone.
+                    None if self.cancelled.remove(&request) => return Ok(None),
+                    None => {
+                        return Err(DownloadError::InvalidBlock {
+                            index: *index,
+                            begin: *begin,
+                            length: block.len(),
+                        })
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(Some(message))
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), DownloadError> {
+        self.state.sent(&message);
+        self.stream.send(message).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Requests a block, which is refused unless the peer lets us request its piece right now.
+    async fn request(&mut self, request: BlockRequest) -> Result<(), DownloadError> {
+        let index = request.index as usize;
+        if !self.can_request(index) {
+            return Err(DownloadError::Choked { index });
+        }
+        self.send(Message::Request {
+            index: request.index,
+            begin: request.begin,
+            length: request.length,
+        })
+        .await?;
+        self.outstanding.insert(request, Instant::now());
+        Ok(())
+    }
+
+    /// Cancels the outstanding requests for blocks of piece `index`.
+    async fn cancel(&mut self, index: usize) -> Result<(), DownloadError> {
+        let requests: Vec<BlockRequest> = self
+            .outstanding
+            .keys()
+            .filter(|request| request.index as usize == index)
+            .copied()
+            .collect();
+        for request in requests {
+            self.outstanding.remove(&request);
+            self.send(Message::Cancel {
+                index: request.index,
+                begin: request.begin,
+                length: request.length,
+            })
+            .await?;
+            self.cancelled.insert(request);
+        }
+        Ok(())
+    }
+
+    /// How many requests the peer is willing to queue, as announced in its extended handshake.
+    fn max_requests(&self) -> usize {
+        self.extensions
+            .peer_handshake()
+            .and_then(|handshake| handshake.reqq)
+            .map_or(DEFAULT_MAX_REQUESTS, |reqq| (reqq as usize).max(1))
+    }
+
+    /// Sends Interested or NotInterested if whether the peer has any of the wanted pieces changed.
+    async fn update_interest(&mut self) -> Result<(), DownloadError> {
+        let interested = self.bitfield.intersection(&self.wanted).count() > 0;
+        if interested != self.state.am_interested {
+            let message = if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the pieces we still need, e.g. after other peers provided some of them.
+    async fn set_wanted(&mut self, wanted: Bitfield) -> Result<(), DownloadError> {
+        self.wanted = wanted;
+        self.update_interest().await
+    }
+
+    /// When a keep-alive has to be sent if nothing else is sent before.
+    fn keep_alive_at(&self) -> Instant {
+        self.last_sent + KEEP_ALIVE_INTERVAL
+    }
+
+    /// Whether the peer announced piece `index` in its bitfield or a Have message.
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.bitfield.has(index)
+    }
+
+    /// The pieces the peer has.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Whether piece `index` may be requested from the peer right now.
+    fn can_request(&self, index: usize) -> bool {
+        self.bitfield.has(index)
+            && !self.rejected.has(index)
+            && (!self.state.am_choked || self.allowed_fast.has(index))
+    }
+
+    /// The pieces that may be requested from the peer right now.
+    fn requestable(&self) -> Bitfield {
+        let allowed = if self.state.am_choked {
+            self.bitfield.intersection(&self.allowed_fast)
+        } else {
+            self.bitfield.clone()
+        };
+        allowed.difference(&self.rejected)
+    }
+
+    /// Whether the peer chokes us.
+    fn is_choked(&self) -> bool {
+        self.state.am_choked
+    }
+
+    /// Whether we told the peer it has pieces we want.
+    fn is_interested(&self) -> bool {
+        self.state.am_interested
+    }
+
+    /// Requests every block of piece `index` and returns the piece once its hash has been checked
+    /// against the one in the torrent. If the peer chokes us before the piece is complete and does
+    /// not allow it fast, the piece is given up with [`DownloadError::Choked`].
+    pub async fn download(&mut self, info: &Info, index: usize) -> Result<Vec<u8>, DownloadError> {
+        if index >= info.pieces.data.len() {
+            return Err(DownloadError::InvalidPiece { index });
+        }
+        self.start(info, index, None);
+        loop {
+            self.fill_pipeline().await?;
+            match self.advance().await?.pop() {
+                Some((_, Finished::Verified(piece))) => return Ok(piece),
+                Some((_, Finished::GivenUp(_, e) | Finished::Failed(e))) => return Err(e),
+                None => {}
+            }
+        }
+    }
+
+    /// Starts downloading piece `index`, whose blocks are then requested by `fill_pipeline`. Only
+    /// the missing blocks of a `partial` piece are requested.
+    fn start(&mut self, info: &Info, index: usize, partial: Option<PartialPiece>) {
+        let piece_size = info.piece_size(index);
+        let partial = partial.unwrap_or_else(|| PartialPiece {
+            data: vec![0; piece_size],
+            missing: (0..piece_size)
+                .step_by(BLOCK_SIZE)
+                .map(|begin| BlockRequest {
+                    index: index as u32,
+                    begin: begin as u32,
+                    length: BLOCK_SIZE.min(piece_size - begin) as u32,
+                })
+                .collect(),
+        });
+        self.active.push(ActivePiece {
+            index,
+            expected: info.pieces.data[index],
+            data: partial.data,
+            pending: partial.missing,
+            requested: Vec::new(),
+        });
+    }
+
+    /// Stops downloading the active pieces and returns the blocks received of them.
+    fn hand_back(&mut self) -> Vec<(usize, PartialPiece)> {
+        self.active
+            .drain(..)
+            .map(|piece| (piece.index, piece.into_partial()))
+            .collect()
+    }
+
+    /// Whether every block of the active pieces is requested and the pipeline still has room, so
+    /// that another piece should be started to keep the peer busy.
+    fn wants_piece(&self) -> bool {
+        self.outstanding.len() < self.pipeline.depth(self.max_requests())
+            && self.active.iter().all(|piece| piece.pending.is_empty())
+    }
+
+    /// Requests blocks of the active pieces, oldest piece first, until as many requests are
+    /// outstanding as the pipeline of the peer calls for.
+    async fn fill_pipeline(&mut self) -> Result<(), DownloadError> {
+        let depth = self.pipeline.depth(self.max_requests());
+        for i in 0..self.active.len() {
+            while self.outstanding.len() < depth && self.can_request(self.active[i].index) {
+                let Some(request) = self.active[i].pending.pop_front() else {
+                    break;
+                };
+                self.request(request).await?;
+                self.active[i].requested.push(request);
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes the next message from the peer and returns the pieces it finished. Pieces the
+    /// peer does not let us request anymore are given up with [`DownloadError::Choked`] or
+    /// [`DownloadError::Rejected`], so that another peer can take over. An error means the
+    /// connection is of no more use, the active pieces have to be handed back.
+    async fn advance(&mut self) -> Result<Vec<FinishedPiece>, DownloadError> {
+        let given_up = self.give_up_unrequestable().await?;
+        if !given_up.is_empty() {
+            return Ok(given_up);
+        }
+
+        let mut finished = Vec::new();
+        // Blocks only get here for outstanding requests, which all belong to active pieces.
+        if let Message::Piece {
+            index,
+            begin,
+            block,
+        } = self.recv().await?
+        {
+            if let Some(position) = self
+                .active
+                .iter()
+                .position(|piece| piece.index == index as usize)
+            {
+                let piece = &mut self.active[position];
+                piece.requested.retain(|request| request.begin != begin);
+                piece.data[begin as usize..][..block.len()].copy_from_slice(&block);
+                if piece.pending.is_empty() && piece.requested.is_empty() {
+                    let piece = self.active.remove(position);
+                    finished.push((piece.index, piece.verify()));
+                }
+            }
+        }
+        // Requests dropped by the peer are sent again as long as the piece may still be requested.
+        for piece in &mut self.active {
+            piece.requested.retain(|request| {
+                let outstanding = self.outstanding.contains_key(request);
+                if !outstanding {
+                    piece.pending.push_back(*request);
+                }
+                outstanding
+            });
+        }
+        Ok(finished)
+    }
+
+    /// Cancels the requests of the active pieces that may not be requested anymore, and returns
+    /// them as given up.
+    async fn give_up_unrequestable(&mut self) -> Result<Vec<FinishedPiece>, DownloadError> {
+        let mut given_up = Vec::new();
+        let mut i = 0;
+        while i < self.active.len() {
+            let index = self.active[i].index;
+            if self.can_request(index) {
+                i += 1;
+                continue;
+            }
+            let piece = self.active.remove(i);
+            self.cancel(index).await?;
+            let error = if self.rejected.has(index) {
+                DownloadError::Rejected { index }
+            } else {
+                DownloadError::Choked { index }
+            };
+            given_up.push((index, Finished::GivenUp(piece.into_partial(), error)));
+        }
+        Ok(given_up)
+    }
+}
+
+impl ActivePiece {
+    /// Checks the complete piece against its hash from the torrent.
+    fn verify(self) -> Finished {
+        let actual: [u8; 20] = Sha1::digest(&self.data).into();
+        if actual != self.expected {
+            return Finished::Failed(DownloadError::HashMismatch {
+                index: self.index,
+                expected: self.expected,
+                actual,
+            });
+        }
+        Finished::Verified(self.data)
+    }
+
+    /// The blocks received so far, with requested blocks counted as missing.
+    fn into_partial(self) -> PartialPiece {
+        let mut missing = self.pending;
+        missing.extend(self.requested);
+        PartialPiece {
+            data: self.data,
+            missing,
+        }
+    }
+}
+
+async fn next_message(
+    stream: &mut Framed<TcpStream, MessageFramer>,
+) -> Result<Message, DownloadError> {
+    stream
+        .next()
+        .await
+        .ok_or(DownloadError::ConnectionClosed)?
+        .map_err(DownloadError::from)
+}
+
+/// Pieces that still have to be downloaded, shared between all peer workers.
+struct WorkQueue {
+    state: Mutex<WorkQueueState>,
+    notify: Notify,
+}
+
+struct WorkQueueState {
+    /// Pieces neither downloaded nor handed to a peer.
+    pending: Bitfield,
+    /// Pieces not downloaded yet.
+    missing: Bitfield,
+    /// Number of pieces currently being downloaded by some peer.
+    in_progress: usize,
+    /// Blocks received of pending pieces that peers handed back.
+    partial: HashMap<usize, PartialPiece>,
+    picker: Box<dyn PiecePicker>,
+}
+
+impl WorkQueueState {
+    /// Hands out the pending piece the picker chooses among the `requestable` ones, along with
+    /// the blocks received of it before.
+    fn take(&mut self, requestable: &Bitfield) -> Option<(usize, Option<PartialPiece>)> {
+        let index = self.picker.pick(&requestable.intersection(&self.pending))?;
+        self.pending.unset(index);
+        self.in_progress += 1;
+        Some((index, self.partial.remove(&index)))
+    }
+}
+
+impl WorkQueue {
+    fn new(nr_of_pieces: usize, picker: Box<dyn PiecePicker>) -> Self {
+        Self {
+            state: Mutex::new(WorkQueueState {
+                pending: Bitfield::full(nr_of_pieces),
+                missing: Bitfield::full(nr_of_pieces),
+                in_progress: 0,
+                partial: HashMap::new(),
+                picker,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Takes the pending piece the picker chooses among those the peer can provide right now.
+    /// While other peers are still working, a piece this peer could serve may be handed back, and
+    /// while the peer chokes us, it may unchoke us later on, so it waits for either, reading from
+    /// the peer in the meantime. Returns `None` once there is nothing left that this peer can help
+    /// with.
+    async fn next(
+        &self,
+        downloader: &mut PieceDownloader,
+    ) -> Result<Option<(usize, Option<PartialPiece>)>, DownloadError> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(next) = state.take(&downloader.requestable()) {
+                    return Ok(Some(next));
+                }
+                let may_unchoke = downloader.is_choked() && downloader.is_interested();
+                if state.in_progress == 0 && !may_unchoke {
+                    return Ok(None);
+                }
+            }
+            tokio::select! {
+                _ = notified => {}
+                message = downloader.next_frame() => {
+                    downloader.process(message?).await?;
+                }
+            }
+        }
+    }
+
+    /// Like `next`, but does not wait for pieces other peers may hand back.
+    fn try_next(&self, downloader: &PieceDownloader) -> Option<(usize, Option<PartialPiece>)> {
+        self.state.lock().unwrap().take(&downloader.requestable())
+    }
+
+    fn complete(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.in_progress -= 1;
+        state.missing.unset(index);
+        state.picker.completed(index);
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Counts the pieces of a peer towards how many peers have them.
+    fn add_availability(&self, pieces: &Bitfield) {
+        self.state.lock().unwrap().picker.add_availability(pieces);
+    }
+
+    fn remove_availability(&self, pieces: &Bitfield) {
+        self.state
+            .lock()
+            .unwrap()
+            .picker
+            .remove_availability(pieces);
+    }
+
+    /// The pieces no peer has downloaded yet.
+    fn missing(&self) -> Bitfield {
+        self.state.lock().unwrap().missing.clone()
+    }
+
+    /// Hands piece `index` back to be downloaded by another peer, which only has to download what
+    /// is missing of `partial`.
+    fn retry(&self, index: usize, partial: Option<PartialPiece>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_progress -= 1;
+        state.pending.set(index);
+        if let Some(partial) = partial {
+            state.partial.insert(index, partial);
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+}
+
+/// Downloads the whole torrent by spreading its pieces over every peer that completes the
+/// handshake, and writes the verified files to `output`. The tracker is kept informed of our
+/// progress and asked for more peers whenever its interval has passed. `peers` are contacted
+/// right away, before the tracker is asked for more.
+pub async fn download_all(
+    info: Arc<Info>,
+    mut tracker: TrackerSession,
+    config: PeerConfig,
+    peers: Vec<SocketAddr>,
+    output: &Path,
+) -> Result<(), Error> {
+    let mut downloaded = 0;
+    let result = run_download(
+        info.clone(),
+        &mut tracker,
+        config,
+        peers,
+        output,
+        &mut downloaded,
+    )
+    .await;
+    let left = info.length() - downloaded;
+    if result.is_ok() {
+        if let Err(e) = announce_final(&mut tracker, Event::Completed, downloaded, left).await {
+            eprintln!("Could not announce completion: {}", e);
+        }
+    }
+    if let Err(e) = announce_final(&mut tracker, Event::Stopped, downloaded, left).await {
+        eprintln!("Could not announce shutdown: {}", e);
+    }
+    result
+}
+
+/// Announces the end of the download, giving up after a short while.
+async fn announce_final(
+    tracker: &mut TrackerSession,
+    event: Event,
+    downloaded: usize,
+    left: usize,
+) -> Result<(), Error> {
+    timeout(
+        FINAL_ANNOUNCE_TIMEOUT,
+        tracker.announce(Some(event), 0, downloaded, left),
+    )
+    .await
+    .map_err(|_| Error::msg("Trackers did not respond in time."))??;
+    Ok(())
+}
+
+/// An announce in progress, which hands the tracker back along with the peers it got.
+type Announcing<'a> = BoxFuture<'a, (&'a mut TrackerSession, Result<Vec<SocketAddr>, Error>)>;
+
+async fn run_download(
+    info: Arc<Info>,
+    tracker: &mut TrackerSession,
+    config: PeerConfig,
+    peers: Vec<SocketAddr>,
+    output: &Path,
+    downloaded_bytes: &mut usize,
+) -> Result<(), Error> {
+    let nr_of_pieces = info.pieces.data.len();
+    let queue = Arc::new(WorkQueue::new(
+        nr_of_pieces,
+        Box::new(RarestFirst::new(nr_of_pieces)),
+    ));
+    let (tx, mut rx) = mpsc::unbounded_channel::<(usize, Vec<u8>)>();
+    let mut workers = JoinSet::new();
+    // Peers with a worker, which are not connected to again until the worker is done.
+    let mut known_peers = HashSet::new();
+    let (pex_tx, mut pex_rx) = mpsc::unbounded_channel();
+    let connected = Arc::new(ConnectedPeers::new(pex_tx));
+
+    let mut storage = Storage::create(&info, output).await?;
+    let mut completed = Bitfield::new(nr_of_pieces);
+    // Set once every peer is gone, so that we give up if the next announce brings no new ones.
+    let mut out_of_peers = false;
+    let worker = |address| {
+        let download = download_from_peer(
+            address,
+            config,
+            info.clone(),
+            queue.clone(),
+            tx.clone(),
+            connected.clone(),
+        );
+        async move { (address, download.await) }
+    };
+    for address in peers {
+        if workers.len() < MAX_PEERS && known_peers.insert(address) {
+            workers.spawn(worker(address));
+        }
+    }
+    // Right away for a new session, or when due if the tracker was already asked for peers.
+    let mut announce_at = tracker.next_announce();
+    // The announce runs alongside the peers, owning the tracker until it is done, so that slow
+    // trackers do not hold up the download. It is cancelled if the download ends first.
+    let mut idle_tracker = Some(tracker);
+    let mut announcing: Option<Announcing> = None;
+    while !completed.is_complete() {
+        tokio::select! {
+            // Pieces go first, so that the pieces of a peer are stored before its worker is seen
+            // to be done and we possibly give up for lack of peers.
+            biased;
+            Some((index, piece)) = rx.recv() => {
+                storage.write_piece(index, &piece).await?;
+                completed.set(index);
+                *downloaded_bytes += piece.len();
+                println!(
+                    "Piece {} downloaded ({}/{}).",
+                    index,
+                    completed.count(),
+                    nr_of_pieces
+                );
+            }
+            Some(address) = pex_rx.recv() => {
+                if workers.len() < MAX_PEERS && known_peers.insert(address) {
+                    workers.spawn(worker(address));
+                }
+            }
+            Some(result) = workers.join_next() => {
+                let (address, result) = result?;
+                if let Err(e) = result {
+                    println!("Dropping peer: {}", e);
+                }
+                // The tracker or other peers may hand out the peer again, e.g. once it has more
+                // pieces or is reachable again.
+                known_peers.remove(&address);
+                if workers.is_empty() {
+                    out_of_peers = true;
+                    // An announce that is already running will tell whether there are more peers.
+                    if let Some(tracker) = &idle_tracker {
+                        announce_at = tracker.earliest_announce();
+                    }
+                }
+            }
+            _ = sleep_until(announce_at), if idle_tracker.is_some() => {
+                let tracker = idle_tracker.take().expect("no announce is running");
+                let downloaded = *downloaded_bytes;
+                let left = info.length() - downloaded;
+                announcing = Some(
+                    async move {
+                        let peers = tracker.announce(None, 0, downloaded, left).await;
+                        (tracker, peers)
+                    }
+                    .boxed(),
+                );
+            }
+            Some((tracker, peers)) = OptionFuture::from(announcing.as_mut()) => {
+                announcing = None;
+                let peers = match peers {
+                    Ok(peers) => {
+                        announce_at = tracker.next_announce();
+                        peers
+                    }
+                    Err(e) if !out_of_peers => {
+                        eprintln!("Announce failed: {}", e);
+                        announce_at = Instant::now() + ANNOUNCE_RETRY_DELAY;
+                        Vec::new()
+                    }
+                    Err(e) => return Err(e),
+                };
+                idle_tracker = Some(tracker);
+                for address in peers {
+                    if workers.len() < MAX_PEERS && known_peers.insert(address) {
+                        workers.spawn(worker(address));
+                    }
+                }
+                if workers.is_empty() {
+                    return Err(Error::msg(format!(
+                        "No peers left, downloaded {} of {} pieces.",
+                        completed.count(),
+                        nr_of_pieces
+                    )));
+                }
+                out_of_peers = false;
+            }
+        }
+    }
+    storage.flush().await?;
+    Ok(())
+}
+
+/// Worker that keeps taking pieces from the queue for as long as the peer has some we need.
+async fn download_from_peer(
+    address: SocketAddr,
+    config: PeerConfig,
+    info: Arc<Info>,
+    queue: Arc<WorkQueue>,
+    tx: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+    connected: Arc<ConnectedPeers>,
+) -> Result<(), DownloadError> {
+    println!("Connecting to the peer. Address = {}", address);
+    let extensions = ExtensionRegistry::new(vec![Box::new(PexExtension::new(
+        address,
+        connected.clone(),
+    ))]);
+    let mut downloader =
+        PieceDownloader::connect(address, config, queue.missing(), extensions).await?;
+    let flags = if downloader.bitfield().is_complete() {
+        pex::FLAG_REACHABLE | pex::FLAG_SEED
+    } else {
+        pex::FLAG_REACHABLE
+    };
+    connected.insert(address, flags);
+    let mut announced = downloader.bitfield().clone();
+    queue.add_availability(&announced);
+    let result = download_pieces(&mut downloader, &info, &queue, &tx, &mut announced).await;
+    // Pieces still being downloaded when the peer failed are left to the others.
+    for (index, partial) in downloader.hand_back() {
+        queue.retry(index, Some(partial));
+    }
+    queue.remove_availability(&announced);
+    connected.remove(address);
+    result
+}
+
+async fn download_pieces(
+    downloader: &mut PieceDownloader,
+    info: &Info,
+    queue: &WorkQueue,
+    tx: &mpsc::UnboundedSender<(usize, Vec<u8>)>,
+    announced: &mut Bitfield,
+) -> Result<(), DownloadError> {
+    loop {
+        // Pieces are taken from the queue before the active ones are done, so that the requests
+        // for the next piece are already on their way.
+        if downloader.wants_piece() {
+            // Pieces the peer got since, which `announced` did not count towards availability.
+            let added = downloader.bitfield().difference(announced);
+            if added.count() > 0 {
+                queue.add_availability(&added);
+                *announced = downloader.bitfield().clone();
+            }
+            // Other peers may have provided everything we wanted from this one.
+            downloader.set_wanted(queue.missing()).await?;
+
+            let idle = downloader.active.is_empty();
+            let next = if idle {
+                queue.next(downloader).await?
+            } else {
+                queue.try_next(downloader)
+            };
+            match next {
+                Some((index, partial)) => {
+                    downloader.start(info, index, partial);
+                    continue;
+                }
+                None if idle => break,
+                None => {}
+            }
+        }
+
+        downloader.fill_pipeline().await?;
+        let mut error = None;
+        for (index, finished) in downloader.advance().await? {
+            match finished {
+                Finished::Verified(piece) => {
+                    queue.complete(index);
+                    // The receiver only goes away once the download is over.
+                    let _ = tx.send((index, piece));
+                }
+                Finished::GivenUp(partial, _) => {
+                    // Another peer may be willing to serve the rest of the piece.
+                    queue.retry(index, Some(partial));
+                }
+                Finished::Failed(e) => {
+                    // Let another peer download the piece anew and stop using this one.
+                    queue.retry(index, None);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_starts_with_min_requests() {
+        let pipeline = Pipeline::default();
+        assert_eq!(pipeline.depth(DEFAULT_MAX_REQUESTS), MIN_REQUESTS);
+        assert_eq!(pipeline.depth(2), 2);
+    }
+
+    #[test]
+    fn pipeline_covers_rate_times_latency() {
+        let pipeline = Pipeline {
+            // 10 blocks per second with a round trip of 1.5 s: 15 blocks in flight, plus one.
+            rate: 10.0 * BLOCK_SIZE as f64,
+            latency: Some(Duration::from_millis(1500)),
+            last_block: None,
+        };
+        assert_eq!(pipeline.depth(DEFAULT_MAX_REQUESTS), 16);
+        // Never more than the peer is willing to queue.
+        assert_eq!(pipeline.depth(10), 10);
+    }
+
+    #[test]
+    fn pipeline_measures_blocks() {
+        let mut pipeline = Pipeline::default();
+        let requested_at = Instant::now() - Duration::from_millis(500);
+        pipeline.on_block(BLOCK_SIZE, requested_at);
+        let latency = pipeline.latency.unwrap();
+        assert!(latency >= Duration::from_millis(500));
+        // A block every half second at most, which fits in the minimum queue.
+        assert!(pipeline.rate <= 2.0 * BLOCK_SIZE as f64);
+        assert_eq!(pipeline.depth(DEFAULT_MAX_REQUESTS), MIN_REQUESTS);
+
+        // The latency is the shortest round trip seen.
+        pipeline.on_block(BLOCK_SIZE, Instant::now() - Duration::from_millis(100));
+        assert!(pipeline.latency.unwrap() < latency);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Error;
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{collections::BTreeMap, net::SocketAddr};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use crate::peer::{Message, MessageFramer};
+
+/// Extended message id of the extended handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+/// Number of outstanding requests we advertise to the peer in the `reqq` field.
+const MAX_REQUESTS: u32 = 250;
+
+/// Payload of the extended handshake (BEP 10). Every field is optional, and peers may send keys we
+/// do not know about.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+    /// Maps names of supported extensions to the extended message id the sender wants to
+    /// receive them with. An id of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Local TCP listen port of the sender.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Client name and version.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Our IP address as seen by the sender, in compact form (4 or 16 bytes).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// Number of outstanding requests the sender accepts without dropping any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// Size of the info dictionary in bytes (BEP 9).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+/// An extension that can be plugged into a peer connection.
+pub trait ExtensionHandler: Send + Sync {
+    /// Name the extension is registered under in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+
+    /// Lets the extension add its own keys to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called when the peer's extended handshake arrives. `supported` tells whether the peer
+    /// offers this extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake, _supported: bool) {}
+
+    /// Handles a message the peer sent for this extension and returns the payloads to reply with.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// Payloads the extension wants to send on its own initiative, polled regularly.
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// The extensions of a single peer connection. Our extended message id of an extension is its
+/// position in the registry plus one.
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    peer_handshake: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(handlers: Vec<Box<dyn ExtensionHandler>>) -> Self {
+        assert!(handlers.len() < u8::MAX as usize, "too many extensions");
+        Self {
+            handlers,
+            peer_handshake: None,
+        }
+    }
+
+    /// Our extended handshake for the peer at `address`.
+    pub fn handshake(&self, address: SocketAddr) -> Result<Message, Error> {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .handlers
+                .iter()
+                .enumerate()
+                .map(|(i, handler)| (handler.name().to_string(), i as i64 + 1))
+                .collect(),
+            v: Some(concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string()),
+            yourip: Some(ByteBuf::from(match address {
+                SocketAddr::V4(address) => address.ip().octets().to_vec(),
+                SocketAddr::V6(address) => address.ip().octets().to_vec(),
+            })),
+            reqq: Some(MAX_REQUESTS),
+            ..Default::default()
+        };
+        for handler in &self.handlers {
+            handler.extend_handshake(&mut handshake);
+        }
+        Ok(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: serde_bencode::to_bytes(&handshake)?,
+        })
+    }
+
+    /// The peer's extended handshake, once it has arrived.
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// The extended message id the peer wants to receive the extension `name` with, if it
+    /// supports it.
+    pub fn peer_id_of(&self, name: &str) -> Option<u8> {
+        let id = *self.peer_handshake.as_ref()?.m.get(name)?;
+        u8::try_from(id).ok().filter(|&id| id != HANDSHAKE_ID)
+    }
+
+    /// Handles an extended message from the peer and returns the messages to send back.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, Error> {
+        if id == HANDSHAKE_ID {
+            let handshake: ExtendedHandshake = serde_bencode::from_bytes(payload)?;
+            for handler in self.handlers.iter_mut() {
+                let supported = handshake
+                    .m
+                    .get(handler.name())
+                    .is_some_and(|&id| id > 0 && id <= u8::MAX as i64);
+                handler.on_handshake(&handshake, supported);
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(Vec::new());
+        }
+
+        let handler = self
+            .handlers
+            .get_mut(id as usize - 1)
+            .ok_or(Error::msg(format!("Unknown extended message id {}.", id)))?;
+        let name = handler.name();
+        let replies = handler.on_message(payload)?;
+        Ok(self.wrap(name, replies))
+    }
+
+    /// Collects the messages extensions want to send on their own.
+    pub fn poll(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for i in 0..self.handlers.len() {
+            let payloads = self.handlers[i].poll();
+            let name = self.handlers[i].name();
+            messages.extend(self.wrap(name, payloads));
+        }
+        messages
+    }
+
+    /// Addresses payloads of the extension `name` to the peer, dropping them if the peer does not
+    /// support it.
+    fn wrap(&self, name: &str, payloads: Vec<Vec<u8>>) -> Vec<Message> {
+        match self.peer_id_of(name) {
+            Some(id) => payloads
+                .into_iter()
+                .map(|payload| Message::Extended { id, payload })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Sends our extended handshake and waits for the peer's, skipping the regular messages that
+/// arrive before it.
+pub async fn exchange_handshakes(
+    stream: &mut Framed<TcpStream, MessageFramer>,
+    registry: &mut ExtensionRegistry,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    stream.send(registry.handshake(address)?).await?;
+    while registry.peer_handshake().is_none() {
+        match stream.next().await {
+            Some(Ok(Message::Extended { id, payload })) => {
+                for reply in registry.handle(id, &payload)? {
+                    stream.send(reply).await?;
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(Error::msg("Peer closed the connection.")),
+        }
+    }
+    Ok(())
+}
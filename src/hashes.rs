@@ -5,9 +5,6 @@ pub struct Hashes {
     pub data: Vec<[u8; 20]>,
 }
 
-#[derive(Debug)]
-pub struct SingleHash([u8; 20]);
-
 struct HashesVisitor;
 
 impl<'de> Visitor<'de> for HashesVisitor {
@@ -53,12 +50,3 @@ impl Serialize for Hashes {
         serializer.serialize_bytes(res.as_slice())
     }
 }
-
-impl Serialize for SingleHash {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_str(&urlencoding::encode_binary(&self.0))
-    }
-}
@@ -0,0 +1,168 @@
+use anyhow::Error;
+use std::net::SocketAddr;
+
+use crate::torrent::{Info, Torrent};
+
+/// A magnet link (BEP 9): enough to find peers of a torrent and ask them for its info
+/// dictionary.
+#[derive(Debug)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Suggested name of the torrent, from `dn`.
+    pub display_name: Option<String>,
+    /// Tracker URLs, from `tr`.
+    pub trackers: Vec<String>,
+    /// Peers to contact directly, from `x.pe`.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    /// Parses a `magnet:?xt=urn:btih:...` URI. The info hash may be given as 40 hex digits or as
+    /// 32 base32 characters.
+    pub fn parse(uri: &str) -> Result<Self, Error> {
+        let url = reqwest::Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            return Err(Error::msg(format!("{} is not a magnet link.", uri)));
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            // Parameters may be numbered when given more than once, e.g. `tr.1`.
+            let key = match key.rsplit_once('.') {
+                Some((key, n)) if n.bytes().all(|b| b.is_ascii_digit()) => key,
+                _ => &key,
+            };
+            match key {
+                "xt" => {
+                    // Other topics, like the `btmh` hash of v2 torrents, are not supported.
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => {
+                    // `x.pe` is `host:port`, where the host may also be `[ipv6]`. Only literal
+                    // addresses parse, so peers named by host name are left out.
+                    if let Ok(peer) = value.parse() {
+                        peers.push(peer);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(Error::msg("Magnet link has no BitTorrent info hash."))?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    /// Builds the torrent once its info dictionary has been fetched from a peer and checked
+    /// against the info hash. Every tracker of the link becomes a tier of its own.
+    pub fn to_torrent(&self, info: &[u8]) -> Result<Torrent, Error> {
+        let info = serde_bencode::from_bytes::<Info>(info)?;
+        info.validate()?;
+        Ok(Torrent {
+            announce: self.trackers.first().cloned().unwrap_or_default(),
+            announce_list: Some(self.tiers()),
+            info,
+            info_hash: self.info_hash,
+        })
+    }
+
+    /// The trackers as tiers of a single URL each, in the order they appear in the link.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|url| vec![url.clone()]).collect()
+    }
+}
+
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], Error> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash)?,
+        32 => {
+            base32_decode(hash).ok_or(Error::msg(format!("Invalid base32 info hash {}.", hash)))?
+        }
+        _ => return Err(Error::msg(format!("Invalid info hash {}.", hash))),
+    };
+    Ok(bytes.try_into().expect("hash has 20 bytes"))
+}
+
+/// Decodes unpadded base32 (RFC 4648), ignoring case.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32_decodes_any_case() {
+        assert_eq!(base32_decode("MZXW6YTBOI"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("mzxw6ytboi"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW6YTB1I"), None);
+    }
+
+    #[test]
+    fn parses_magnet_link() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+             &tr.1=udp%3A%2F%2Ftracker.example%3A6969&x.pe=127.0.0.1:6881&x.pe=peer.example:1",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "udp://tracker.example:6969",
+            ]
+        );
+        assert_eq!(magnet.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parses_base32_info_hash() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_magnet_links() {
+        assert!(Magnet::parse("magnet:?dn=name").is_err());
+        assert!(
+            Magnet::parse("http://example.com/?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF")
+                .is_err()
+        );
+    }
+}
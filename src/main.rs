@@ -1,265 +1,358 @@
 use anyhow::Error;
 use clap::Parser;
-use futures::{sink::SinkExt, stream::StreamExt};
-use hex;
 use rand::{seq::SliceRandom, thread_rng};
-use reqwest;
-use sha1::{Digest, Sha1};
-use std::{fs, mem::size_of, path::Path};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{collections::HashMap, fs, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
 
 mod args;
+mod bencode;
+mod bitfield;
+mod download;
+mod extension;
 mod hashes;
+mod magnet;
+mod metadata;
 mod peer;
+mod pex;
+mod picker;
+mod scrape;
+mod storage;
+mod torrent;
 mod tracker;
+mod tracker_server;
+mod udp_tracker;
+mod udp_tracker_server;
 
-use hashes::Hashes;
-
-#[derive(Debug, serde::Deserialize)]
-struct Torrent {
-    announce: String,
-
-    info: Info,
-}
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Deserialize, Serialize)]
-struct Info {
-    length: usize,
-
-    name: String,
-
-    #[serde(rename = "piece length")]
-    piece_length: usize,
-
-    pieces: Hashes,
-}
-
-impl Info {
-    pub fn calc_hash(&self) -> [u8; 20] {
-        let info_ser = serde_bencode::to_bytes(self).expect("Could not serialize");
-        let mut hasher = Sha1::new();
-        hasher.update(info_ser);
-        let info_hash = hasher.finalize();
-        info_hash.into()
-    }
-}
+use bitfield::Bitfield;
+use download::PieceDownloader;
+use torrent::read_torrent;
 
 const MY_PEER_ID: &str = "00112233445566778899";
-const BLOCK_SIZE: usize = 1 << 14;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let args = args::Args::parse();
+    let my_peer_id: [u8; 20] = MY_PEER_ID.as_bytes().try_into()?;
     match args.command {
+        args::Commands::Decode { value, file } => {
+            let encoded = match (value, file) {
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(file)) => fs::read(file)?,
+                (None, None) => unreachable!("clap requires either a value or a file"),
+            };
+            let decoded: serde_bencode::value::Value = serde_bencode::from_bytes(&encoded)?;
+            println!("{}", bencode::to_json(&decoded));
+        }
+        args::Commands::Info { torrent, json } => {
+            print_info(&read_torrent(torrent)?, json)?;
+        }
+        args::Commands::Peers { torrent } => {
+            let torrent = read_torrent(torrent)?;
+            for peer in tracker::get_peers(&torrent, MY_PEER_ID).await? {
+                println!("{}", peer);
+            }
+        }
+        args::Commands::Scrape { torrents } => {
+            let torrents = torrents
+                .into_iter()
+                .map(read_torrent)
+                .collect::<Result<Vec<_>, _>>()?;
+            // Ask every tracker only once, for all of its torrents together.
+            let mut by_tracker: HashMap<&str, Vec<[u8; 20]>> = HashMap::new();
+            for torrent in &torrents {
+                match torrent.primary_tracker() {
+                    Some(tracker) => by_tracker
+                        .entry(tracker)
+                        .or_default()
+                        .push(torrent.info_hash),
+                    None => eprintln!("{} has no trackers.", torrent.info.name),
+                }
+            }
+            let mut stats = HashMap::new();
+            for (tracker, info_hashes) in by_tracker {
+                match scrape::scrape(tracker, &info_hashes).await {
+                    Ok(result) => stats.extend(result),
+                    Err(e) => eprintln!("Scrape of {} failed: {}", tracker, e),
+                }
+            }
+            for torrent in &torrents {
+                let info_hash = hex::encode(torrent.info_hash);
+                match stats.get(&torrent.info_hash) {
+                    Some(s) => println!(
+                        "{} {}: complete {}, downloaded {}, incomplete {}",
+                        info_hash, torrent.info.name, s.complete, s.downloaded, s.incomplete
+                    ),
+                    None => println!("{} {}: unknown", info_hash, torrent.info.name),
+                }
+            }
+        }
+        args::Commands::Handshake { torrent, peer } => {
+            let torrent = read_torrent(torrent)?;
+            let (stream, handshake) = peer::connect(peer, torrent.info_hash, my_peer_id).await?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            println!("Reserved: {}", hex::encode(handshake.reserved));
+            if handshake.supports_extensions() {
+                let mut stream = Framed::new(
+                    stream,
+                    peer::MessageFramer::for_torrent(
+                        torrent.info.pieces.data.len(),
+                        download::BLOCK_SIZE,
+                    ),
+                );
+                let mut extensions = extension::ExtensionRegistry::new(Vec::new());
+                let exchanged = timeout(
+                    Duration::from_secs(10),
+                    extension::exchange_handshakes(&mut stream, &mut extensions, peer),
+                )
+                .await;
+                // The handshake itself succeeded, the extended one is only extra information.
+                match exchanged {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        println!("Extensions: extended handshake failed: {}", e);
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        println!("Extensions: peer sent no extended handshake in time");
+                        return Ok(());
+                    }
+                }
+                let peer_handshake = extensions
+                    .peer_handshake()
+                    .expect("handshakes were exchanged");
+                println!("Extensions: {:?}", peer_handshake.m);
+                if let Some(v) = &peer_handshake.v {
+                    println!("Client: {}", v);
+                }
+                if let Some(reqq) = peer_handshake.reqq {
+                    println!("Request Queue: {}", reqq);
+                }
+            }
+        }
+        args::Commands::Tracker {
+            bind,
+            port,
+            udp,
+            interval,
+        } => {
+            let swarms = Arc::new(tracker_server::Swarms::new(Duration::from_secs(interval)));
+            tokio::spawn(swarms.clone().run_expiry());
+            let http = tracker_server::serve_http(SocketAddr::new(bind, port), swarms.clone());
+            match udp {
+                Some(udp) => {
+                    let udp = udp_tracker_server::serve_udp(SocketAddr::new(bind, udp), swarms);
+                    tokio::try_join!(http, udp)?;
+                }
+                None => http.await?,
+            }
+        }
         args::Commands::Download {
             output_file,
             torrent,
+            idle_timeout,
         } => {
-            let index = 0;
-            let torrent: Torrent = read_torrent(torrent);
-            let request = tracker::TrackerRequest {
-                //info_hash: SingleHash(torrent.info.calc_hash()),
-                peer_id: MY_PEER_ID.to_string(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: torrent.info.length,
-                compact: 1,
-            };
-            let params = serde_urlencoded::to_string(request).expect("url encode failed");
-
-            let full_url = format!(
-                "{}?info_hash={}&{}",
-                torrent.announce,
-                urlencoding::encode_binary(&torrent.info.calc_hash()),
-                params
+            let torrent = read_torrent(torrent)?;
+            let info_hash = torrent.info_hash;
+            let tracker = tracker::TrackerSession::new(
+                tracker::TrackerList::new(&torrent),
+                info_hash,
+                MY_PEER_ID,
             );
+            let name = torrent.info.name.clone();
+            let config = download::PeerConfig {
+                info_hash,
+                peer_id: my_peer_id,
+                idle_timeout: Duration::from_secs(idle_timeout),
+            };
+            download::download_all(
+                Arc::new(torrent.info),
+                tracker,
+                config,
+                Vec::new(),
+                &output_file,
+            )
+            .await?;
+            println!("Downloaded {} to {}.", name, output_file.display());
+        }
+        args::Commands::DownloadPiece {
+            output_file,
+            torrent,
+            index,
+            idle_timeout,
+        } => {
+            let torrent = read_torrent(torrent)?;
+            let nr_of_pieces = torrent.info.pieces.data.len();
+            if index >= nr_of_pieces {
+                return Err(Error::msg(format!(
+                    "Piece {} does not exist, the torrent has {} pieces.",
+                    index, nr_of_pieces
+                )));
+            }
+            let mut peers = tracker::get_peers(&torrent, MY_PEER_ID).await?;
+            peers.shuffle(&mut thread_rng());
+            let config = download::PeerConfig {
+                info_hash: torrent.info_hash,
+                peer_id: my_peer_id,
+                idle_timeout: Duration::from_secs(idle_timeout),
+            };
 
-            let response = reqwest::get(full_url)
+            let mut last_error = Error::msg("List of peers should not be empty.");
+            for address in peers {
+                println!("Connecting to the peer. Address = {}", address);
+                let mut wanted = Bitfield::new(nr_of_pieces);
+                wanted.set(index);
+                let mut downloader = match PieceDownloader::connect(
+                    address,
+                    config,
+                    wanted,
+                    extension::ExtensionRegistry::new(Vec::new()),
+                )
                 .await
-                .expect("GET for peers failed")
-                .bytes()
-                .await
-                .unwrap();
-            let response: tracker::TrackerResponse = serde_bencode::from_bytes(&*response)?;
-            let peers = match response {
-                tracker::TrackerResponse::Error { failure_reason } => Err(Error::msg(format!(
-                    "Peer request failed. Reason: {}",
-                    failure_reason
-                ))),
-                tracker::TrackerResponse::Peers {
-                    interval: _,
-                    min_interval: _,
-                    tracker_id: _,
-                    complete: _,
-                    incomplete: _,
-                    peers,
-                } => Ok(peers),
-            }?;
-            let peer_address = peers
-                .choose(&mut thread_rng())
-                .expect("List of peers should not be empty.");
-            let my_peer_id = MY_PEER_ID.as_bytes().to_owned().try_into().unwrap();
-            let info_hash = torrent.info.calc_hash();
-
-            let mut streams = futures::stream::iter(peers.iter())
-                .map(|address| async move {
-                    let my_handshake = peer::Handshake::new(info_hash, my_peer_id);
-                    println!("Connecting to the peer. Address = {}", address);
-                    let mut stream = tokio::net::TcpStream::connect(address).await?;
-                    println!("Sending handshake. Address = {}", address);
-                    {
-                        let my_handshake_bytes = my_handshake.to_bytes();
-                        stream.write_all(&my_handshake_bytes.as_slice()).await?;
+                {
+                    Ok(downloader) => downloader,
+                    Err(e) => {
+                        last_error = e.into();
+                        continue;
                     }
-
-                    println!("Receiving handshake. Address = {}", address);
-                    let peer_handshake = {
-                        let mut peer_handshake_bytes = vec![0; size_of::<peer::Handshake>()];
-                        stream
-                            .read_exact(peer_handshake_bytes.as_mut_slice())
-                            .await?;
-                        peer::Handshake::from_bytes(peer_handshake_bytes.as_slice())
-                            .ok_or(Error::msg("Invalid size for handshake"))?
-                    };
-                    if my_handshake.info_hash != peer_handshake.info_hash {
-                        return Err(Error::msg("info_hash from the peer does not match."));
+                };
+                if !downloader.has_piece(index) {
+                    last_error =
+                        Error::msg(format!("Peer {} does not have piece {}", address, index));
+                    continue;
+                }
+                match downloader.download(&torrent.info, index).await {
+                    Ok(piece) => {
+                        tokio::fs::write(&output_file, piece).await?;
+                        println!("Piece {} downloaded to {}.", index, output_file.display());
+                        return Ok(());
                     }
-                    Ok(stream)
-                })
-                .buffer_unordered(5)
-                .collect::<Vec<Result<tokio::net::TcpStream, Error>>>()
-                .await;
-
-            let stream = streams[0].as_mut().unwrap();
-
-            /*
-            let mut stream = tokio_util::codec::Framed::new(stream, peer::MessageFramer);
-
-            // 1. step: wait for Bitfield message
-            println!("Waiting for Bitfield message.");
-            let next_msg = stream
-                .next()
-                .await
-                .expect("expecting messages")
-                .expect("should be a valid message");
-            println!("Received message is {:?}", next_msg);
-
-            match next_msg {
-                peer::Message::Bitfield(_) => {}
-                _ => {
-                    return Err(Error::msg(format!(
-                        "Unexpected message type: Expected Bitfield, got {:?}",
-                        next_msg
-                    )))
+                    Err(e) => last_error = e.into(),
                 }
             }
-            // 2. step: send Interested message.
-            println!("Sending Interested message.");
-            stream.send(peer::Message::Interested).await?;
-            // 3. step: wait for the Unchoke message.
-            println!("Waiting for Unchoke message.");
-            let next_msg = stream
-                .next()
-                .await
-                .expect("expecting messages")
-                .expect("should be a valid message");
-            println!("Received message is {:?}", next_msg);
-
-            match next_msg {
-                peer::Message::Unchoke => {}
-                _ => {
-                    return Err(Error::msg(format!(
-                        "Unexpected message type: Expected Unchoke, got {:?}",
-                        next_msg
-                    )))
-                }
+            return Err(last_error);
+        }
+        args::Commands::MagnetParse { link } => {
+            let magnet = magnet::Magnet::parse(&link)?;
+            for tracker in &magnet.trackers {
+                println!("Tracker URL: {}", tracker);
             }
-
-            // 4. step: send request for all blocks
-            let requested_piece_size = if index == torrent.info.pieces.data.len() - 1 {
-                torrent.info.length % torrent.info.piece_length
-            } else {
-                torrent.info.piece_length
-            };
-
-            println!("requested piece size = {}", requested_piece_size);
-
-            let nr_of_blocks = requested_piece_size.div_ceil(BLOCK_SIZE);
-            assert!(nr_of_blocks > 1);
-
-            let mut piece_data: Vec<Vec<u8>> = vec![Vec::new(); nr_of_blocks];
-
-            for block_nr in 0..nr_of_blocks {
-                let cur_block_size = if block_nr != nr_of_blocks - 1 {
-                    // not last block, always of block size.
-                    BLOCK_SIZE
-                } else if requested_piece_size % BLOCK_SIZE == 0 {
-                    BLOCK_SIZE
-                } else {
-                    // last block and piece if not multiple of block size.
-                    requested_piece_size % BLOCK_SIZE
-                };
-                println!("Sending Request message: index: {}", index);
-                println!("                         block: {}", block_nr);
-                println!("                         len:   {}", cur_block_size);
-                let request = peer::Message::Request {
-                    index: index as u32,
-                    begin: (block_nr * BLOCK_SIZE) as u32,
-                    length: cur_block_size as u32,
-                };
-
-                stream.send(request).await?;
-
-                // 4. step: receive piece message
-                let block = stream
-                    .next()
-                    .await
-                    .expect("expecting piece message")
-                    .expect("should be a valid message");
-                let (received_index, begin, data) = match block {
-                    peer::Message::Piece {
-                        index,
-                        begin,
-                        block,
-                    } => (index, begin, block),
-                    _ => {
-                        return Err(Error::msg(format!(
-                            "Unexpected message type: Expected Piece, got {:?}",
-                            next_msg
-                        )))
-                    }
-                };
-                assert!(index == received_index as usize);
-                assert!(begin as usize % BLOCK_SIZE == 0);
-                assert!(data.len() == cur_block_size);
-                let block_index = begin as usize / BLOCK_SIZE;
-                println!("Block received: index: {}", index);
-                println!("                block: {}", block_index);
-                piece_data[block_index] = data;
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+            if let Some(name) = &magnet.display_name {
+                println!("Name: {}", name);
             }
-            let mut hasher = Sha1::new();
-            for block in piece_data {
-                hasher.update(block);
+            for peer in &magnet.peers {
+                println!("Peer: {}", peer);
             }
-            let piece_hash = hasher.finalize();
-            println!(
-                "torrent hash:  {}",
-                hex::encode(torrent.info.pieces.data[index])
+        }
+        args::Commands::MagnetInfo { link, json } => {
+            let magnet = magnet::Magnet::parse(&link)?;
+            let mut tracker = tracker::TrackerSession::new(
+                tracker::TrackerList::from_tiers(magnet.tiers()),
+                magnet.info_hash,
+                MY_PEER_ID,
             );
-            println!("received hash: {}", hex::encode(piece_hash));
-
-            */
+            let peers = magnet_peers(&magnet, &mut tracker).await?;
+            let info = metadata::fetch(&peers, magnet.info_hash, my_peer_id).await?;
+            print_info(&magnet.to_torrent(&info)?, json)?;
+        }
+        args::Commands::MagnetDownload {
+            output_file,
+            link,
+            idle_timeout,
+        } => {
+            let magnet = magnet::Magnet::parse(&link)?;
+            let info_hash = magnet.info_hash;
+            let mut tracker = tracker::TrackerSession::new(
+                tracker::TrackerList::from_tiers(magnet.tiers()),
+                info_hash,
+                MY_PEER_ID,
+            );
+            let peers = magnet_peers(&magnet, &mut tracker).await?;
+            let info = metadata::fetch(&peers, info_hash, my_peer_id).await?;
+            let torrent = magnet.to_torrent(&info)?;
+            let name = torrent.info.name.clone();
+            let config = download::PeerConfig {
+                info_hash,
+                peer_id: my_peer_id,
+                idle_timeout: Duration::from_secs(idle_timeout),
+            };
+            download::download_all(Arc::new(torrent.info), tracker, config, peers, &output_file)
+                .await?;
+            println!("Downloaded {} to {}.", name, output_file.display());
         }
     }
     Ok(())
 }
 
-fn read_torrent<P>(path: P) -> Torrent
-where
-    P: AsRef<Path>,
-{
-    let contents = fs::read(path).expect("Could not read file");
-    serde_bencode::from_bytes(contents.as_slice()).expect("Could not deserialize")
+/// The peers of a magnet link: those given in the link itself and those its trackers know of.
+/// The size of the torrent is unknown until the metadata arrives, so the trackers are told that
+/// something is left to download.
+async fn magnet_peers(
+    magnet: &magnet::Magnet,
+    tracker: &mut tracker::TrackerSession,
+) -> Result<Vec<SocketAddr>, Error> {
+    let mut peers = magnet.peers.clone();
+    if magnet.trackers.is_empty() {
+        return Ok(peers);
+    }
+    match tracker.announce(None, 0, 0, 1).await {
+        Ok(announced) => peers.extend(announced.into_iter().filter(|p| !magnet.peers.contains(p))),
+        Err(e) if !peers.is_empty() => eprintln!("Announce failed: {}", e),
+        Err(e) => return Err(e),
+    }
+    Ok(peers)
+}
+
+/// Prints the contents of a torrent, as text or as JSON.
+fn print_info(torrent: &torrent::Torrent, json: bool) -> Result<(), Error> {
+    let info_hash = hex::encode(torrent.info_hash);
+    let piece_hashes: Vec<String> = torrent.info.pieces.data.iter().map(hex::encode).collect();
+    let files = match &torrent.info.keys {
+        torrent::Keys::SingleFile { .. } => None,
+        torrent::Keys::MultiFile { files } => Some(files),
+    };
+    // Torrents with an `announce-list` may leave out `announce`.
+    let tracker_url = match torrent.announce.as_str() {
+        "" => torrent.primary_tracker().unwrap_or_default(),
+        announce => announce,
+    };
+    if json {
+        let mut info = serde_json::json!({
+            "tracker_url": tracker_url,
+            "length": torrent.info.length(),
+            "info_hash": info_hash,
+            "piece_length": torrent.info.piece_length,
+            "piece_hashes": piece_hashes,
+        });
+        if let Some(announce_list) = &torrent.announce_list {
+            info["announce_list"] = serde_json::to_value(announce_list)?;
+        }
+        if let Some(files) = files {
+            info["files"] = serde_json::to_value(files)?;
+        }
+        println!("{}", info);
+    } else {
+        println!("Tracker URL: {}", tracker_url);
+        if let Some(announce_list) = &torrent.announce_list {
+            println!("Announce List:");
+            for (tier, urls) in announce_list.iter().enumerate() {
+                println!("{} {}", tier, urls.join(" "));
+            }
+        }
+        println!("Length: {}", torrent.info.length());
+        println!("Info Hash: {}", info_hash);
+        println!("Piece Length: {}", torrent.info.piece_length);
+        println!("Piece Hashes:");
+        for hash in piece_hashes {
+            println!("{}", hash);
+        }
+        if let Some(files) = files {
+            println!("Files:");
+            for file in files {
+                println!("{} {}", file.length, file.path.join("/"));
+            }
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,210 @@
+use anyhow::Error;
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::extension::{self, ExtendedHandshake, ExtensionHandler, ExtensionRegistry};
+use crate::peer::{self, Message, MessageFramer};
+
+pub const NAME: &str = "ut_metadata";
+/// The info dictionary is transferred in pieces of 16 KiB, only the last one may be shorter.
+const PIECE_SIZE: usize = 1 << 14;
+/// Peers announcing bigger metadata than this are not believed.
+const MAX_METADATA_SIZE: usize = 1 << 24;
+/// How long a single peer gets to hand over the whole info dictionary.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MSG_REQUEST: u8 = 0;
+const MSG_DATA: u8 = 1;
+const MSG_REJECT: u8 = 2;
+
+/// Header of a `ut_metadata` message. Data messages are followed by the piece itself.
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// The metadata extension (BEP 9), downloading the info dictionary of a torrent we only know the
+/// info hash of. Requests from the peer are rejected, as we have nothing to serve yet.
+pub struct MetadataExtension {
+    info_hash: [u8; 20],
+    size: Option<usize>,
+    pieces: Vec<Option<Vec<u8>>>,
+    requested: bool,
+    /// Receives the info dictionary once it is complete and verified.
+    metadata: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl MetadataExtension {
+    pub fn new(info_hash: [u8; 20], metadata: Arc<Mutex<Option<Vec<u8>>>>) -> Self {
+        Self {
+            info_hash,
+            size: None,
+            pieces: Vec::new(),
+            requested: false,
+            metadata,
+        }
+    }
+
+    fn encode(message: &MetadataMessage) -> Vec<u8> {
+        serde_bencode::to_bytes(message).expect("metadata messages can be encoded")
+    }
+}
+
+impl ExtensionHandler for MetadataExtension {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake, supported: bool) {
+        if let Some(size) = handshake.metadata_size {
+            if supported && is_valid_size(size) {
+                self.size = Some(size);
+                self.pieces = vec![None; (size + PIECE_SIZE - 1) / PIECE_SIZE];
+            }
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let header_len =
+            crate::bencode::value_len(payload).ok_or(Error::msg("Invalid metadata message."))?;
+        let message: MetadataMessage = serde_bencode::from_bytes(&payload[..header_len])?;
+        match message.msg_type {
+            MSG_REQUEST => Ok(vec![Self::encode(&MetadataMessage {
+                msg_type: MSG_REJECT,
+                piece: message.piece,
+                total_size: None,
+            })]),
+            MSG_DATA => {
+                let size = self.size.ok_or(Error::msg(
+                    "Peer sent metadata without announcing its size.",
+                ))?;
+                let data = &payload[header_len..];
+                // The piece number comes from the peer, so it is checked before computing with it.
+                let valid = message.piece < self.pieces.len()
+                    && data.len() == PIECE_SIZE.min(size - message.piece * PIECE_SIZE);
+                if !valid {
+                    return Err(Error::msg(format!(
+                        "Invalid metadata piece {} of {} bytes.",
+                        message.piece,
+                        data.len()
+                    )));
+                }
+                self.pieces[message.piece] = Some(data.to_vec());
+                if self.pieces.iter().all(Option::is_some) {
+                    let metadata: Vec<u8> = self.pieces.drain(..).flatten().flatten().collect();
+                    if <[u8; 20]>::from(Sha1::digest(&metadata)) != self.info_hash {
+                        return Err(Error::msg("Metadata does not match the info hash."));
+                    }
+                    *self.metadata.lock().unwrap() = Some(metadata);
+                }
+                Ok(Vec::new())
+            }
+            MSG_REJECT => Err(Error::msg(format!(
+                "Peer rejected the request for metadata piece {}.",
+                message.piece
+            ))),
+            // Unknown message types must be ignored.
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        if self.requested || self.pieces.is_empty() {
+            return Vec::new();
+        }
+        self.requested = true;
+        (0..self.pieces.len())
+            .map(|piece| {
+                Self::encode(&MetadataMessage {
+                    msg_type: MSG_REQUEST,
+                    piece,
+                    total_size: None,
+                })
+            })
+            .collect()
+    }
+}
+
+fn is_valid_size(size: usize) -> bool {
+    size > 0 && size <= MAX_METADATA_SIZE
+}
+
+/// Asks the peers one after the other for the info dictionary of the torrent, until one of them
+/// hands over a dictionary matching the info hash.
+pub async fn fetch(
+    peers: &[SocketAddr],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Result<Vec<u8>, Error> {
+    let mut last_error = Error::msg("List of peers should not be empty.");
+    for &address in peers {
+        println!("Fetching metadata from the peer. Address = {}", address);
+        match timeout(PEER_TIMEOUT, fetch_from_peer(address, info_hash, peer_id)).await {
+            Ok(Ok(metadata)) => return Ok(metadata),
+            Ok(Err(e)) => last_error = e,
+            Err(_) => last_error = Error::msg(format!("Peer {} timed out.", address)),
+        }
+        eprintln!("Could not fetch metadata: {}", last_error);
+    }
+    Err(last_error)
+}
+
+async fn fetch_from_peer(
+    address: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Result<Vec<u8>, Error> {
+    let (stream, handshake) = peer::connect(address, info_hash, peer_id).await?;
+    if !handshake.supports_extensions() {
+        return Err(Error::msg("Peer does not support the extension protocol."));
+    }
+    // The bitfield arrives before the metadata tells us the number of pieces, so it may be as
+    // large as the biggest metadata we accept allows.
+    let mut stream = Framed::new(
+        stream,
+        MessageFramer::for_torrent(MAX_METADATA_SIZE / 20, PIECE_SIZE),
+    );
+    let metadata = Arc::new(Mutex::new(None));
+    let mut registry = ExtensionRegistry::new(vec![Box::new(MetadataExtension::new(
+        info_hash,
+        metadata.clone(),
+    ))]);
+    extension::exchange_handshakes(&mut stream, &mut registry, address).await?;
+    let size = registry
+        .peer_handshake()
+        .expect("handshakes were exchanged")
+        .metadata_size;
+    if registry.peer_id_of(NAME).is_none() || !size.is_some_and(is_valid_size) {
+        return Err(Error::msg("Peer does not offer the metadata."));
+    }
+
+    loop {
+        for message in registry.poll() {
+            stream.send(message).await?;
+        }
+        if let Some(metadata) = metadata.lock().unwrap().take() {
+            return Ok(metadata);
+        }
+        match stream.next().await {
+            Some(Ok(Message::Extended { id, payload })) => {
+                for reply in registry.handle(id, &payload)? {
+                    stream.send(reply).await?;
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(Error::msg("Peer closed the connection.")),
+        }
+    }
+}
@@ -1,9 +1,14 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use int_enum::IntEnum;
-use std::io::{self, Cursor, Read};
+use std::io::{self, Cursor};
 use std::mem::size_of;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio_util::codec::{Decoder, Encoder};
 
+use crate::bitfield::Bitfield;
+
 #[derive(Default)]
 pub struct Handshake {
     pub protocol_len: u8,
@@ -13,25 +18,45 @@ pub struct Handshake {
     pub peer_id: [u8; 20],
 }
 
+/// Bit in the 6th reserved byte announcing support for the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+/// Bit in the last reserved byte announcing support for the Fast Extension (BEP 6).
+const FAST_EXTENSION_BIT: u8 = 0x04;
+
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[5] |= EXTENSION_PROTOCOL_BIT;
+        reserved[7] |= FAST_EXTENSION_BIT;
         Self {
             protocol_len: 19,
             protocol_string: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
 
+    /// Whether the sender of the handshake supports the extension protocol.
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    /// Whether the sender of the handshake supports the Fast Extension.
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & FAST_EXTENSION_BIT != 0
+    }
+
     pub fn from_bytes(buf: &[u8]) -> Option<Self> {
         let mut cur = Cursor::new(buf);
         if cur.remaining() != 1 + 19 + 8 + 20 + 20 {
             return None;
         }
 
-        let mut s = Self::default();
-        s.protocol_len = cur.get_u8();
+        let mut s = Self {
+            protocol_len: cur.get_u8(),
+            ..Default::default()
+        };
         cur.copy_to_slice(&mut s.protocol_string);
         cur.copy_to_slice(&mut s.reserved);
         cur.copy_to_slice(&mut s.info_hash);
@@ -54,6 +79,39 @@ impl Handshake {
     }
 }
 
+/// Connects to `address` and exchanges handshakes with the peer. Fails if the peer answers with a
+/// different info hash. If the peer supports the Fast Extension, HaveNone is sent right after.
+pub async fn connect(
+    address: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> io::Result<(TcpStream, Handshake)> {
+    let my_handshake = Handshake::new(info_hash, peer_id);
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(my_handshake.to_bytes().as_slice()).await?;
+
+    let mut peer_handshake_bytes = vec![0; size_of::<Handshake>()];
+    stream
+        .read_exact(peer_handshake_bytes.as_mut_slice())
+        .await?;
+    let peer_handshake = Handshake::from_bytes(peer_handshake_bytes.as_slice())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid size for handshake"))?;
+    if my_handshake.info_hash != peer_handshake.info_hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "info_hash from the peer does not match.",
+        ));
+    }
+    // With the Fast Extension, the first message must tell the peer which pieces we have, and we
+    // start out without any.
+    if peer_handshake.supports_fast() {
+        let mut have_none = BytesMut::new();
+        MessageFramer::default().encode(Message::HaveNone, &mut have_none)?;
+        stream.write_all(&have_none).await?;
+    }
+    Ok((stream, peer_handshake))
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, IntEnum)]
 pub enum MessageTag {
@@ -66,15 +124,24 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 pub struct RawMessage {
     pub tag: MessageTag,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 #[derive(Debug)]
 pub enum Message {
+    /// A frame without tag or payload, sent to keep the connection open when there is nothing
+    /// else to send.
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
@@ -83,7 +150,8 @@ pub enum Message {
     Have {
         index: u32,
     },
-    Bitfield(Vec<u8>),
+    /// The pieces the sender has. Only sent right after the handshake.
+    Bitfield(Bitfield),
     /// 'request' messages contain an index, begin, and length. The last two are byte offsets. Length is generally a power of
     ///  two unless it gets truncated by the end of the file. All current implementations use 2^14 (16 kiB), and close
     /// connections which request an amount greater than that.
@@ -98,7 +166,7 @@ pub enum Message {
     Piece {
         index: u32,
         begin: u32,
-        block: Vec<u8>,
+        block: Bytes,
     },
     /// 'cancel' messages have the same payload as request messages.They are generally only sent towards the end of a download,
     /// during what's called 'endgame mode'. When a download is almost complete, there's a tendency for the last few pieces
@@ -111,6 +179,32 @@ pub enum Message {
         begin: u32,
         length: u32,
     },
+    /// The sender suggests downloading this piece from it, e.g. because it has it in its cache
+    /// (BEP 6).
+    SuggestPiece {
+        index: u32,
+    },
+    /// Replaces the Bitfield message when the sender has every piece (BEP 6).
+    HaveAll,
+    /// Replaces the Bitfield message when the sender has no pieces (BEP 6).
+    HaveNone,
+    /// The sender will not serve the request with this payload (BEP 6). With the Fast Extension,
+    /// every request is answered either with a piece or with a reject, also on choke.
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The receiver may request this piece even while choked (BEP 6).
+    AllowedFast {
+        index: u32,
+    },
+    /// Message of the extension protocol (BEP 10). `id` 0 is the extended handshake, other ids
+    /// select the extension as registered in the receiver's handshake `m` dictionary.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl TryFrom<RawMessage> for Message {
@@ -120,19 +214,13 @@ impl TryFrom<RawMessage> for Message {
         let error_payload_not_empty = |tag: MessageTag| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!(
-                    "{:?} message type does not suppose to have payload.",
-                    value.tag
-                ),
+                format!("{:?} message type does not suppose to have payload.", tag),
             )
         };
         let error_invalid_size = |tag: MessageTag, len: usize| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!(
-                    "{:?} message type has invalid payload length {}.",
-                    value.tag, len
-                ),
+                format!("{:?} message type has invalid payload length {}.", tag, len),
             )
         };
         match value.tag {
@@ -140,36 +228,47 @@ impl TryFrom<RawMessage> for Message {
             | MessageTag::Unchoke
             | MessageTag::Interested
             | MessageTag::NotInterested
+            | MessageTag::HaveAll
+            | MessageTag::HaveNone
                 if !value.payload.is_empty() =>
             {
                 Err(error_payload_not_empty(value.tag))
             }
-            MessageTag::Have if value.payload.len() != 4 => {
+            MessageTag::Have | MessageTag::SuggestPiece | MessageTag::AllowedFast
+                if value.payload.len() != 4 =>
+            {
                 Err(error_invalid_size(value.tag, value.payload.len()))
             }
-            MessageTag::Bitfield if value.payload.len() == 0 => {
+            MessageTag::Bitfield if value.payload.is_empty() => {
                 Err(error_invalid_size(value.tag, value.payload.len()))
             }
-            MessageTag::Request | MessageTag::Cancel if value.payload.len() != 12 => {
+            MessageTag::Request | MessageTag::Cancel | MessageTag::RejectRequest
+                if value.payload.len() != 12 =>
+            {
                 Err(error_invalid_size(value.tag, value.payload.len()))
             }
             MessageTag::Piece if value.payload.len() < 9 => {
                 Err(error_invalid_size(value.tag, value.payload.len()))
             }
+            MessageTag::Extended if value.payload.is_empty() => {
+                Err(error_invalid_size(value.tag, value.payload.len()))
+            }
 
             MessageTag::Choke => Ok(Message::Choke),
             MessageTag::Unchoke => Ok(Message::Unchoke),
             MessageTag::Interested => Ok(Message::Interested),
             MessageTag::NotInterested => Ok(Message::NotInterested),
             MessageTag::Have => {
-                let mut cur = io::Cursor::new(value.payload);
+                let mut cur = value.payload;
                 Ok(Message::Have {
                     index: cur.get_u32(),
                 })
             }
-            MessageTag::Bitfield => Ok(Message::Bitfield(value.payload)),
+            MessageTag::Bitfield => Ok(Message::Bitfield(Bitfield::from_payload(
+                value.payload.to_vec(),
+            ))),
             MessageTag::Request => {
-                let mut cur = io::Cursor::new(value.payload);
+                let mut cur = value.payload;
                 Ok(Message::Request {
                     index: cur.get_u32(),
                     begin: cur.get_u32(),
@@ -177,35 +276,61 @@ impl TryFrom<RawMessage> for Message {
                 })
             }
             MessageTag::Piece => {
-                let mut cur = io::Cursor::new(value.payload);
+                let mut cur = value.payload;
                 Ok(Message::Piece {
                     index: cur.get_u32(),
                     begin: cur.get_u32(),
-                    block: {
-                        // take the rest of the buffer
-                        let pos = cur.position() as usize;
-                        let mut v = cur.into_inner();
-                        v.drain(0..pos);
-                        v
-                    },
+                    // The rest of the frame, without copying it.
+                    block: cur,
                 })
             }
             MessageTag::Cancel => {
-                let mut cur = io::Cursor::new(value.payload);
+                let mut cur = value.payload;
                 Ok(Message::Cancel {
                     index: cur.get_u32(),
                     begin: cur.get_u32(),
                     length: cur.get_u32(),
                 })
             }
+            MessageTag::SuggestPiece => {
+                let mut cur = value.payload;
+                Ok(Message::SuggestPiece {
+                    index: cur.get_u32(),
+                })
+            }
+            MessageTag::HaveAll => Ok(Message::HaveAll),
+            MessageTag::HaveNone => Ok(Message::HaveNone),
+            MessageTag::RejectRequest => {
+                let mut cur = value.payload;
+                Ok(Message::RejectRequest {
+                    index: cur.get_u32(),
+                    begin: cur.get_u32(),
+                    length: cur.get_u32(),
+                })
+            }
+            MessageTag::AllowedFast => {
+                let mut cur = value.payload;
+                Ok(Message::AllowedFast {
+                    index: cur.get_u32(),
+                })
+            }
+            MessageTag::Extended => {
+                let mut payload = value.payload;
+                let id = payload.get_u8();
+                Ok(Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                })
+            }
         }
     }
 }
 
-impl Into<RawMessage> for Message {
-    fn into(self) -> RawMessage {
-        let mut payload: Vec<u8> = Vec::new();
-        let tag = match self {
+impl From<Message> for RawMessage {
+    fn from(value: Message) -> Self {
+        let mut payload = BytesMut::new();
+        let tag = match value {
+            Message::KeepAlive => unreachable!("keep-alives have no tag and are framed directly"),
             Message::Choke => MessageTag::Choke,
             Message::Unchoke => MessageTag::Unchoke,
             Message::Interested => MessageTag::Interested,
@@ -215,7 +340,7 @@ impl Into<RawMessage> for Message {
                 MessageTag::Have
             }
             Message::Bitfield(b) => {
-                payload = b;
+                payload.extend_from_slice(b.as_bytes());
                 MessageTag::Bitfield
             }
             Message::Request {
@@ -231,11 +356,11 @@ impl Into<RawMessage> for Message {
             Message::Piece {
                 index,
                 begin,
-                mut block,
+                block,
             } => {
                 payload.put_u32(index);
                 payload.put_u32(begin);
-                payload.append(&mut block);
+                payload.extend_from_slice(&block);
                 MessageTag::Piece
             }
             Message::Cancel {
@@ -248,43 +373,130 @@ impl Into<RawMessage> for Message {
                 payload.put_u32(length);
                 MessageTag::Cancel
             }
+            Message::SuggestPiece { index } => {
+                payload.put_u32(index);
+                MessageTag::SuggestPiece
+            }
+            Message::HaveAll => MessageTag::HaveAll,
+            Message::HaveNone => MessageTag::HaveNone,
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                payload.put_u32(index);
+                payload.put_u32(begin);
+                payload.put_u32(length);
+                MessageTag::RejectRequest
+            }
+            Message::AllowedFast { index } => {
+                payload.put_u32(index);
+                MessageTag::AllowedFast
+            }
+            Message::Extended {
+                id,
+                payload: extended_payload,
+            } => {
+                payload.put_u8(id);
+                payload.extend_from_slice(&extended_payload);
+                MessageTag::Extended
+            }
         };
-        RawMessage { tag, payload }
+        RawMessage {
+            tag,
+            payload: payload.freeze(),
+        }
     }
 }
 
-pub struct MessageFramer;
+/// Frames messages with a length prefix. Frames longer than `max_size` are refused in both
+/// directions, so that a peer cannot make us buffer arbitrary amounts of data.
+pub struct MessageFramer {
+    max_size: usize,
+}
+
+/// Limit for connections where the torrent is not known yet. Large enough for a 16 KiB block and
+/// for a metadata piece of the extension protocol.
+const DEFAULT_MAX_SIZE: usize = 1 << 15;
 
-const MAX_SIZE: usize = 1 << 15;
+impl MessageFramer {
+    /// Limit fitting the largest legitimate message of a torrent with `nr_of_pieces` pieces,
+    /// whose blocks are requested with at most `block_size` bytes: either its bitfield or a
+    /// piece message.
+    pub fn for_torrent(nr_of_pieces: usize, block_size: usize) -> Self {
+        let bitfield = 1 + (nr_of_pieces + 7) / 8;
+        let piece = 1 + 8 + block_size;
+        Self {
+            max_size: bitfield.max(piece).max(DEFAULT_MAX_SIZE),
+        }
+    }
+}
+
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
 
 impl Encoder<Message> for MessageFramer {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let item: RawMessage = item.into();
-        let full_size = item.payload.len() + 1;
-        // Don't send a message if it is longer than the other end will
-        // accept.
-        if full_size > MAX_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", full_size),
-            ));
+        match item {
+            Message::KeepAlive => {
+                dst.put_u32(0);
+                Ok(())
+            }
+            // Blocks are written straight from their buffer instead of being copied into a
+            // payload first.
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut header = [0; 8];
+                (&mut header[..4]).put_u32(index);
+                (&mut header[4..]).put_u32(begin);
+                write_frame(dst, self.max_size, MessageTag::Piece, &header, &block)
+            }
+            item => {
+                let item: RawMessage = item.into();
+                write_frame(dst, self.max_size, item.tag, &item.payload, &[])
+            }
         }
+    }
+}
 
-        // Convert the length into a byte array.
-        // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(full_size as u32);
+/// Writes a frame whose payload consists of `header` followed by `body`.
+fn write_frame(
+    dst: &mut BytesMut,
+    max_size: usize,
+    tag: MessageTag,
+    header: &[u8],
+    body: &[u8],
+) -> Result<(), io::Error> {
+    let full_size = 1 + header.len() + body.len();
+    // Don't send a message if it is longer than the other end will
+    // accept.
+    if full_size > max_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame of length {} is too large.", full_size),
+        ));
+    }
 
-        // Reserve space in the buffer.
-        dst.reserve(4 + full_size);
+    // Reserve space in the buffer.
+    dst.reserve(4 + full_size);
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(item.payload.as_slice());
-        Ok(())
-    }
+    // Write the length and the message to the buffer.
+    // The cast to u32 cannot overflow due to the length check above.
+    dst.put_u32(full_size as u32);
+    dst.put_u8(tag as u8);
+    dst.extend_from_slice(header);
+    dst.extend_from_slice(body);
+    Ok(())
 }
 
 impl Decoder for MessageFramer {
@@ -304,13 +516,18 @@ impl Decoder for MessageFramer {
 
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX_SIZE {
+        if length > self.max_size {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", length),
             ));
         }
 
+        if length == 0 {
+            src.advance(4);
+            return Ok(Some(Message::KeepAlive));
+        }
+
         if src.len() < 4 + length {
             // The full message has not yet arrived.
             //
@@ -323,11 +540,10 @@ impl Decoder for MessageFramer {
             return Ok(None);
         }
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = src[4];
-        let data = src[5..5 + length - 1].to_vec();
-        src.advance(4 + length);
+        // Split the frame off src, so that the payload can be handed out without copying it.
+        let mut frame = src.split_to(4 + length).freeze();
+        frame.advance(4);
+        let tag = frame.get_u8();
 
         let tag = MessageTag::try_from(tag).map_err(|_| {
             std::io::Error::new(
@@ -335,20 +551,31 @@ impl Decoder for MessageFramer {
                 format!("Message tag {} is invalid.", tag),
             )
         })?;
-        let message = RawMessage { tag, payload: data }.try_into()?;
+        let message = RawMessage {
+            tag,
+            payload: frame,
+        }
+        .try_into()?;
         Ok(Some(message))
     }
 }
 
-struct PeerState {
-    am_interested: bool,
-    am_choked: bool,
-    peer_interested: bool,
-    peer_choked: bool,
+/// Choke and interest state of a connection, from our point of view. Both sides start out choked
+/// and not interested.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerState {
+    /// Whether we told the peer we want pieces from it.
+    pub am_interested: bool,
+    /// Whether the peer chokes us, i.e. does not serve our requests.
+    pub am_choked: bool,
+    /// Whether the peer told us it wants pieces from us.
+    pub peer_interested: bool,
+    /// Whether we choke the peer.
+    pub peer_choked: bool,
 }
 
 impl PeerState {
-    fn new() -> Self {
+    pub fn new() -> Self {
         PeerState {
             am_interested: false,
             am_choked: true,
@@ -356,4 +583,60 @@ impl PeerState {
             peer_choked: true,
         }
     }
+
+    /// Updates the state for a message received from the peer.
+    pub fn received(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.am_choked = true,
+            Message::Unchoke => self.am_choked = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
+
+    /// Updates the state for a message sent to the peer.
+    pub fn sent(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.peer_choked = true,
+            Message::Unchoke => self.peer_choked = false,
+            Message::Interested => self.am_interested = true,
+            Message::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_keep_alive() {
+        let mut src = BytesMut::from(&[0, 0, 0, 0][..]);
+        let message = MessageFramer::default().decode(&mut src).unwrap();
+        assert!(matches!(message, Some(Message::KeepAlive)));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decodes_keep_alive_followed_by_partial_frame() {
+        let mut framer = MessageFramer::default();
+        // A keep-alive, then a Have message of which only the tag has arrived.
+        let mut src = BytesMut::from(&[0, 0, 0, 0, 0, 0, 0, 5, 4][..]);
+        let message = framer.decode(&mut src).unwrap();
+        assert!(matches!(message, Some(Message::KeepAlive)));
+        assert!(framer.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(&[0, 0, 0, 7]);
+        let message = framer.decode(&mut src).unwrap();
+        assert!(matches!(message, Some(Message::Have { index: 7 })));
+        assert!(src.is_empty());
+    }
 }
@@ -0,0 +1,177 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use crate::extension::{ExtendedHandshake, ExtensionHandler};
+use crate::tracker::{compact_peers_v4, compact_peers_v6};
+
+pub const NAME: &str = "ut_pex";
+/// PEX messages must not be sent more often than once a minute.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper limit of added and of dropped peers in a single message.
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+/// The peer is a seed or only uploads. Other flags tell whether it prefers encryption (0x01) or
+/// supports uTP (0x04), neither of which we use.
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer accepts incoming connections, i.e. the connection was made by us.
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// Payload of a `ut_pex` message. Addresses are in compact form, flags have one byte per added
+/// peer.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+/// The peers we are connected to for a download, shared by the PEX extensions of all
+/// connections. Peers learned through PEX are handed to the download through a channel.
+pub struct ConnectedPeers {
+    peers: Mutex<HashMap<SocketAddr, u8>>,
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+}
+
+impl ConnectedPeers {
+    pub fn new(discovered: mpsc::UnboundedSender<SocketAddr>) -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+            discovered,
+        }
+    }
+
+    /// Records a connected peer along with its PEX flags.
+    pub fn insert(&self, address: SocketAddr, flags: u8) {
+        self.peers.lock().unwrap().insert(address, flags);
+    }
+
+    pub fn remove(&self, address: SocketAddr) {
+        self.peers.lock().unwrap().remove(&address);
+    }
+
+    fn snapshot(&self) -> HashMap<SocketAddr, u8> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+/// The peer exchange extension (BEP 11) of a single connection.
+pub struct PexExtension {
+    /// Address of the peer on the other end, which is never sent to itself.
+    address: SocketAddr,
+    peers: Arc<ConnectedPeers>,
+    supported: bool,
+    /// Peers the other end already knows about from our previous messages.
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexExtension {
+    pub fn new(address: SocketAddr, peers: Arc<ConnectedPeers>) -> Self {
+        Self {
+            address,
+            peers,
+            supported: false,
+            sent: HashSet::new(),
+            last_sent: None,
+        }
+    }
+}
+
+impl ExtensionHandler for PexExtension {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake, supported: bool) {
+        self.supported = supported;
+    }
+
+    /// Passes every added peer on to the download. Dropped peers are ignored, as are the flags:
+    /// we neither encrypt nor speak uTP, and seeds are just as welcome as anyone else.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let message: PexMessage = serde_bencode::from_bytes(payload)?;
+        let added = compact_peers_v4(&message.added)
+            .into_iter()
+            .chain(compact_peers_v6(&message.added6));
+        for address in added {
+            // The receiver only goes away once the download is over.
+            let _ = self.peers.discovered.send(address);
+        }
+        Ok(Vec::new())
+    }
+
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        if !self.supported
+            || self
+                .last_sent
+                .is_some_and(|last_sent| last_sent.elapsed() < MIN_INTERVAL)
+        {
+            return Vec::new();
+        }
+
+        let mut connected = self.peers.snapshot();
+        connected.remove(&self.address);
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(address, _)| !self.sent.contains(address))
+            .map(|(&address, &flags)| (address, flags))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|address| !connected.contains_key(address))
+            .copied()
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        let mut message = PexMessage::default();
+        for &(address, flags) in &added {
+            let (peers, peer_flags) = match address {
+                SocketAddr::V4(_) => (&mut message.added, &mut message.added_flags),
+                SocketAddr::V6(_) => (&mut message.added6, &mut message.added6_flags),
+            };
+            write_compact(peers, address);
+            peer_flags.push(flags);
+            self.sent.insert(address);
+        }
+        for &address in &dropped {
+            match address {
+                SocketAddr::V4(_) => write_compact(&mut message.dropped, address),
+                SocketAddr::V6(_) => write_compact(&mut message.dropped6, address),
+            }
+            self.sent.remove(&address);
+        }
+        self.last_sent = Some(Instant::now());
+        vec![serde_bencode::to_bytes(&message).expect("PEX messages can be encoded")]
+    }
+}
+
+/// Appends the address in compact form: the IP address followed by the port, big endian.
+fn write_compact(buf: &mut ByteBuf, address: SocketAddr) {
+    match address {
+        SocketAddr::V4(address) => buf.extend_from_slice(&address.ip().octets()),
+        SocketAddr::V6(address) => buf.extend_from_slice(&address.ip().octets()),
+    }
+    buf.extend_from_slice(&address.port().to_be_bytes());
+}
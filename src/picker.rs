@@ -0,0 +1,141 @@
+use rand::{seq::IteratorRandom, thread_rng};
+
+use crate::bitfield::Bitfield;
+
+/// Number of pieces picked at random before switching to rarest first. Any piece completes faster
+/// than a rare one, which few peers can serve.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Decides in which order the pieces of a torrent are downloaded.
+pub trait PiecePicker: Send {
+    /// A peer has the pieces in `pieces`, announced in its bitfield or in Have messages.
+    fn add_availability(&mut self, pieces: &Bitfield);
+
+    /// A peer that had the pieces in `pieces` is gone.
+    fn remove_availability(&mut self, pieces: &Bitfield);
+
+    /// Chooses the next piece to download among `candidates`, the pieces a peer can serve that
+    /// are neither downloaded nor being downloaded.
+    fn pick(&mut self, candidates: &Bitfield) -> Option<usize>;
+
+    /// Piece `index` was downloaded and verified.
+    fn completed(&mut self, index: usize);
+}
+
+/// Picks the pieces the fewest peers have first, so that they are not lost when those peers
+/// leave. The first few pieces are picked at random instead, and pieces a peer already started
+/// on but handed back go before any others.
+pub struct RarestFirst {
+    /// Number of connected peers that have each piece.
+    availability: Vec<usize>,
+    /// Pieces that were picked before.
+    started: Bitfield,
+    completed: usize,
+}
+
+impl RarestFirst {
+    pub fn new(nr_of_pieces: usize) -> Self {
+        Self {
+            availability: vec![0; nr_of_pieces],
+            started: Bitfield::new(nr_of_pieces),
+            completed: 0,
+        }
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn add_availability(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    fn remove_availability(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    fn pick(&mut self, candidates: &Bitfield) -> Option<usize> {
+        let started = candidates.intersection(&self.started);
+        let candidates = if started.count() > 0 {
+            &started
+        } else {
+            candidates
+        };
+        let mut rng = thread_rng();
+        let index = if self.completed < RANDOM_FIRST_PIECES {
+            candidates.iter().choose(&mut rng)
+        } else {
+            let rarest = candidates
+                .iter()
+                .map(|index| self.availability[index])
+                .min()?;
+            // Ties are broken at random, so that peers do not all go for the same piece.
+            candidates
+                .iter()
+                .filter(|&index| self.availability[index] == rarest)
+                .choose(&mut rng)
+        }?;
+        self.started.set(index);
+        Some(index)
+    }
+
+    fn completed(&mut self, _index: usize) {
+        self.completed += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A picker for 4 pieces where piece 0 is the rarest, with 1 peer against 3 for the others.
+    fn picker(completed: usize) -> RarestFirst {
+        let mut picker = RarestFirst::new(4);
+        let mut common = Bitfield::full(4);
+        common.unset(0);
+        for _ in 0..3 {
+            picker.add_availability(&common);
+        }
+        let mut rare = Bitfield::new(4);
+        rare.set(0);
+        picker.add_availability(&rare);
+        for index in 0..completed {
+            picker.completed(index);
+        }
+        picker
+    }
+
+    #[test]
+    fn picks_at_random_first() {
+        // Picking piece 0 a hundred times in a row by chance is next to impossible.
+        let picked_common = (0..100).any(|_| picker(0).pick(&Bitfield::full(4)) != Some(0));
+        assert!(picked_common);
+    }
+
+    #[test]
+    fn picks_rarest_after_random_first() {
+        let mut picker = picker(RANDOM_FIRST_PIECES);
+        assert_eq!(picker.pick(&Bitfield::full(4)), Some(0));
+    }
+
+    #[test]
+    fn prefers_started_pieces() {
+        let mut picker = picker(RANDOM_FIRST_PIECES);
+        let mut only_2 = Bitfield::new(4);
+        only_2.set(2);
+        assert_eq!(picker.pick(&only_2), Some(2));
+        // Piece 2 was handed back unfinished, and goes before the rarer piece 0.
+        assert_eq!(picker.pick(&Bitfield::full(4)), Some(2));
+    }
+
+    #[test]
+    fn picks_nothing_without_candidates() {
+        assert_eq!(picker(RANDOM_FIRST_PIECES).pick(&Bitfield::new(4)), None);
+    }
+}
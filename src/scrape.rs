@@ -0,0 +1,108 @@
+use anyhow::Error;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+use crate::tracker::http_client;
+use crate::udp_tracker::{UdpTracker, MAX_SCRAPE_HASHES};
+
+/// Swarm statistics of a single torrent, as returned by a scrape.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ScrapeStats {
+    /// Number of peers with the entire file.
+    pub complete: u32,
+    /// Number of times the tracker has registered a completion.
+    pub downloaded: u32,
+    /// Number of non-seeder peers.
+    pub incomplete: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ScrapeResponse {
+    Error {
+        #[serde(rename = "failure reason")]
+        failure_reason: String,
+    },
+    Files {
+        files: HashMap<ByteBuf, ScrapeStats>,
+    },
+}
+
+/// Derives the scrape URL of an HTTP tracker from its announce URL. This is only possible when
+/// the last path component of the announce URL starts with `announce`.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path_end, query) = match announce.find('?') {
+        Some(pos) => (pos, &announce[pos..]),
+        None => (announce.len(), ""),
+    };
+    let slash = announce[..path_end].rfind('/')?;
+    let last = &announce[slash + 1..path_end];
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{}scrape{}{}", &announce[..slash + 1], rest, query))
+}
+
+/// Asks the tracker at `announce` for the statistics of the given torrents. Torrents the tracker
+/// does not know about are missing from the result.
+pub async fn scrape(
+    announce: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, Error> {
+    if announce.starts_with("udp://") {
+        let mut tracker = UdpTracker::new(announce).await?;
+        let mut result = HashMap::new();
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let stats = tracker.scrape(chunk).await?;
+            result.extend(chunk.iter().copied().zip(stats));
+        }
+        return Ok(result);
+    }
+
+    let url = scrape_url(announce).ok_or(Error::msg(format!(
+        "Tracker {} does not support scraping.",
+        announce
+    )))?;
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let params: Vec<String> = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", urlencoding::encode_binary(info_hash)))
+        .collect();
+    let full_url = format!("{}{}{}", url, separator, params.join("&"));
+
+    let response = http_client().get(full_url).send().await?.bytes().await?;
+    let response: ScrapeResponse = serde_bencode::from_bytes(&response)?;
+    match response {
+        ScrapeResponse::Error { failure_reason } => Err(Error::msg(format!(
+            "Scrape request failed. Reason: {}",
+            failure_reason
+        ))),
+        ScrapeResponse::Files { files } => Ok(files
+            .into_iter()
+            .filter_map(|(info_hash, stats)| Some((info_hash.into_vec().try_into().ok()?, stats)))
+            .collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?key=1").as_deref(),
+            Some("http://example.com/x/scrape.php?key=1")
+        );
+    }
+
+    #[test]
+    fn scrape_url_needs_announce_path() {
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+        assert_eq!(scrape_url("http://example.com/x?path=/announce"), None);
+    }
+}
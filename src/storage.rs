@@ -0,0 +1,180 @@
+use anyhow::Error;
+use std::{
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::torrent::{Info, Keys};
+
+/// Maps the contiguous piece space of a torrent onto the files it consists of.
+pub struct Storage {
+    files: Vec<StorageFile>,
+    piece_length: usize,
+}
+
+struct StorageFile {
+    /// Position of the first byte of the file in the piece space.
+    offset: usize,
+    length: usize,
+    file: File,
+}
+
+impl Storage {
+    /// Creates every file of the torrent at its full size. A single-file torrent is written to
+    /// `output` directly, the files of a multi-file torrent go into a directory named after the
+    /// torrent inside `output`.
+    pub async fn create(info: &Info, output: &Path) -> Result<Self, Error> {
+        let paths = match &info.keys {
+            Keys::SingleFile { length } => vec![(output.to_path_buf(), *length)],
+            Keys::MultiFile { files } => {
+                let root = output.join(checked_component(&info.name)?);
+                files
+                    .iter()
+                    .map(|f| {
+                        let mut path = root.clone();
+                        for component in &f.path {
+                            path.push(checked_component(component)?);
+                        }
+                        Ok((path, f.length))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?
+            }
+        };
+
+        let mut files = Vec::with_capacity(paths.len());
+        let mut offset = 0;
+        for (path, length) in paths {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let file = File::create(&path).await?;
+            file.set_len(length as u64).await?;
+            files.push(StorageFile {
+                offset,
+                length,
+                file,
+            });
+            offset += length;
+        }
+
+        Ok(Self {
+            files,
+            piece_length: info.piece_length,
+        })
+    }
+
+    /// Writes a verified piece, splitting it over every file it overlaps.
+    pub async fn write_piece(&mut self, index: usize, piece: &[u8]) -> Result<(), Error> {
+        let start = index * self.piece_length;
+        let end = start + piece.len();
+        for f in &mut self.files {
+            let file_end = f.offset + f.length;
+            if file_end <= start || f.offset >= end {
+                continue;
+            }
+            let from = start.max(f.offset);
+            let to = end.min(file_end);
+            f.file
+                .seek(SeekFrom::Start((from - f.offset) as u64))
+                .await?;
+            f.file.write_all(&piece[from - start..to - start]).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        for f in &mut self.files {
+            f.file.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Makes sure a name coming from the torrent can not escape the download directory.
+fn checked_component(name: &str) -> Result<PathBuf, Error> {
+    let path = PathBuf::from(name);
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(path),
+        _ => Err(Error::msg(format!(
+            "Invalid path component {:?} in torrent.",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashes::Hashes;
+    use crate::torrent::FileEntry;
+
+    fn info(piece_length: usize, length: usize, keys: Keys) -> Info {
+        let nr_of_pieces = (length + piece_length - 1) / piece_length;
+        Info {
+            name: "torrent".to_string(),
+            piece_length,
+            pieces: Hashes {
+                data: vec![[0; 20]; nr_of_pieces],
+            },
+            keys,
+        }
+    }
+
+    /// Writes the pieces of `data` in reverse order, so that no file is written front to back.
+    async fn write_all_pieces(storage: &mut Storage, info: &Info, data: &[u8]) {
+        for index in (0..info.pieces.data.len()).rev() {
+            let start = index * info.piece_length;
+            storage
+                .write_piece(index, &data[start..start + info.piece_size(index)])
+                .await
+                .unwrap();
+        }
+        storage.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_pieces_across_files() {
+        let files = [("a", 3), ("empty", 0), ("c", 2), ("d", 8)];
+        let keys = Keys::MultiFile {
+            files: files
+                .iter()
+                .map(|&(name, length)| FileEntry {
+                    length,
+                    path: vec!["dir".to_string(), name.to_string()],
+                })
+                .collect(),
+        };
+        // The first piece spans all four files, the last file also holds the short last piece.
+        let info = info(6, 13, keys);
+        let data: Vec<u8> = (1..=13).collect();
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut storage = Storage::create(&info, dir.path()).await.unwrap();
+        write_all_pieces(&mut storage, &info, &data).await;
+
+        let mut offset = 0;
+        for (name, length) in files {
+            let path = dir.path().join("torrent").join("dir").join(name);
+            assert_eq!(std::fs::read(path).unwrap(), &data[offset..offset + length]);
+            offset += length;
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_short_last_piece_of_single_file() {
+        let info = info(4, 10, Keys::SingleFile { length: 10 });
+        let data: Vec<u8> = (1..=10).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        let mut storage = Storage::create(&info, &path).await.unwrap();
+        write_all_pieces(&mut storage, &info, &data).await;
+
+        assert_eq!(std::fs::read(path).unwrap(), data);
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{fs, path::Path};
+
+use crate::bencode;
+use crate::hashes::Hashes;
+
+#[derive(Debug, Deserialize)]
+pub struct Torrent {
+    /// Empty when the torrent only has an `announce-list`.
+    #[serde(default)]
+    pub announce: String,
+
+    /// Tiers of tracker URLs (BEP 12). When present, `announce` is ignored.
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    pub info: Info,
+
+    /// SHA-1 of the bencoded `info` dictionary as it appears in the torrent file, so that keys we
+    /// do not model are still part of the hash.
+    #[serde(skip)]
+    pub info_hash: [u8; 20],
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Info {
+    /// In the single file case, the name of the file. In the multiple file case, the name of the
+    /// directory the files are stored in.
+    pub name: String,
+
+    #[serde(rename = "piece length")]
+    pub piece_length: usize,
+
+    pub pieces: Hashes,
+
+    #[serde(flatten)]
+    pub keys: Keys,
+}
+
+/// There is a key `length` or a key `files`, but not both or neither.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Keys {
+    /// If `length` is present then the download represents a single file.
+    SingleFile { length: usize },
+    /// Otherwise it represents a set of files which go in a directory structure.
+    MultiFile { files: Vec<FileEntry> },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileEntry {
+    /// The length of the file in bytes.
+    pub length: usize,
+    /// A list of UTF-8 encoded strings corresponding to subdirectory names, the last of which is
+    /// the actual file name.
+    pub path: Vec<String>,
+}
+
+impl Torrent {
+    /// The tracker to use when only one is needed: the first of the `announce-list` if present,
+    /// `announce` otherwise. `None` if the torrent has no trackers at all.
+    pub fn primary_tracker(&self) -> Option<&str> {
+        self.announce_list
+            .iter()
+            .flatten()
+            .flatten()
+            .next()
+            .or(Some(&self.announce).filter(|announce| !announce.is_empty()))
+            .map(String::as_str)
+    }
+}
+
+impl Info {
+    /// Total number of bytes in the torrent, over all of its files.
+    pub fn length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Checks that the pieces cover the torrent's length exactly, so that every piece has a size.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.piece_length == 0 {
+            return Err(Error::msg("Torrent has a piece length of 0."));
+        }
+        let length = self.length();
+        let expected = length / self.piece_length + usize::from(length % self.piece_length != 0);
+        if expected == 0 {
+            return Err(Error::msg("Torrent has no pieces."));
+        }
+        if self.pieces.data.len() != expected {
+            return Err(Error::msg(format!(
+                "Torrent has {} pieces, but its length of {} needs {} pieces of {} bytes.",
+                self.pieces.data.len(),
+                length,
+                expected,
+                self.piece_length
+            )));
+        }
+        Ok(())
+    }
+
+    /// Size of the piece at `index`. Every piece is `piece_length` long except the last one, which
+    /// holds whatever is left of the torrent.
+    pub fn piece_size(&self, index: usize) -> usize {
+        if index == self.pieces.data.len() - 1 {
+            self.length() - self.piece_length * index
+        } else {
+            self.piece_length
+        }
+    }
+}
+
+pub fn read_torrent<P>(path: P) -> Result<Torrent, Error>
+where
+    P: AsRef<Path>,
+{
+    let contents = fs::read(path)?;
+    let mut torrent: Torrent = serde_bencode::from_bytes(contents.as_slice())?;
+    let info = bencode::raw_dict_value(&contents, b"info")
+        .ok_or(Error::msg("Torrent file has no info dictionary."))?;
+    torrent.info_hash = Sha1::digest(info).into();
+    torrent.info.validate()?;
+    Ok(torrent)
+}
@@ -1,5 +1,22 @@
-use serde::{Deserialize, Deserializer, Serialize};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use anyhow::Error;
+use rand::{seq::SliceRandom, thread_rng};
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::torrent::Torrent;
+use crate::udp_tracker::UdpTracker;
+
+/// HTTP trackers that do not accept the connection, or do not answer, in time count as failed.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Serialize)]
 pub struct TrackerRequest {
@@ -10,8 +27,37 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    /// If a previous announce contained a tracker id, it should be set here.
+    #[serde(rename = "trackerid", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first request to the tracker must include this event.
+    Started,
+    /// Sent when the download completes, but not if the download was already complete when the
+    /// client started.
+    Completed,
+    /// Sent when the client is shutting down gracefully.
+    Stopped,
 }
 
+/// What a tracker told us in response to an announce, regardless of the protocol used.
+#[derive(Debug)]
+pub struct Announce {
+    /// How long to wait before sending the next regular announce.
+    pub interval: Duration,
+    /// Announces must not be sent more often than this.
+    pub min_interval: Option<Duration>,
+    pub tracker_id: Option<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum TrackerResponse {
@@ -20,31 +66,382 @@ pub enum TrackerResponse {
         failure_reason: String,
     },
     Peers {
-        interval: usize,
+        interval: u64,
         #[serde(rename = "min interval")]
-        min_interval: Option<usize>,
+        min_interval: Option<u64>,
         #[serde(rename = "tracker id")]
         tracker_id: Option<String>,
-        complete: usize,
-        incomplete: usize,
-        #[serde(deserialize_with = "deser_socket_addr")]
-        peers: Vec<SocketAddrV4>,
+        /// Number of seeders, which trackers may leave out.
+        complete: Option<usize>,
+        /// Number of leechers, which trackers may leave out.
+        incomplete: Option<usize>,
+        /// Missing when the tracker only has IPv6 peers, which are in `peers6`.
+        #[serde(default, deserialize_with = "deser_socket_addr")]
+        peers: Vec<SocketAddr>,
+        #[serde(default, deserialize_with = "deser_socket_addr_v6")]
+        peers6: Vec<SocketAddr>,
     },
 }
 
-fn deser_socket_addr<'de, D>(deserializer: D) -> Result<Vec<SocketAddrV4>, D::Error>
+/// A peer in the dictionary model of the `peers` list.
+#[derive(Debug, Deserialize)]
+struct PeerEntry {
+    /// IP address (IPv6 hexed, IPv4 dotted quad) or DNS name of the peer.
+    ip: String,
+    port: u16,
+}
+
+struct PeersVisitor;
+
+impl<'de> Visitor<'de> for PeersVisitor {
+    type Value = Vec<SocketAddr>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("compact peer string or list of peer dictionaries.")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(compact_peers_v4(v))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut peers = Vec::new();
+        while let Some(peer) = seq.next_element::<PeerEntry>()? {
+            // Peers given by DNS name are skipped, resolving them is not worth the wait.
+            if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+                peers.push(SocketAddr::new(ip, peer.port));
+            }
+        }
+        Ok(peers)
+    }
+}
+
+fn deser_socket_addr<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(PeersVisitor)
+}
+
+fn deser_socket_addr_v6<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
 where
     D: Deserializer<'de>,
 {
     let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
-    let res = bytes
+    Ok(compact_peers_v6(&bytes))
+}
+
+/// Parses the compact peer format, 4 bytes of IPv4 address followed by a 2 byte port per peer.
+pub fn compact_peers_v4(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
         .chunks_exact(6)
         .map(|buf| {
-            SocketAddrV4::new(
-                Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]),
+            SocketAddr::new(
+                Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]).into(),
                 u16::from_be_bytes([buf[4], buf[5]]),
             )
         })
-        .collect();
-    Ok(res)
+        .collect()
+}
+
+/// Parses the compact IPv6 peer format (BEP 7), 16 bytes of address followed by a 2 byte port.
+pub fn compact_peers_v6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(18)
+        .map(|buf| {
+            let ip: [u8; 16] = buf[..16].try_into().unwrap();
+            SocketAddr::new(
+                Ipv6Addr::from(ip).into(),
+                u16::from_be_bytes([buf[16], buf[17]]),
+            )
+        })
+        .collect()
+}
+
+/// The trackers of a torrent, grouped in tiers as described in BEP 12.
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    /// UDP trackers we talked to, kept around so their connection IDs can be reused.
+    udp_trackers: HashMap<String, UdpTracker>,
+    http: reqwest::Client,
+}
+
+impl TrackerList {
+    /// Uses the torrent's `announce-list` if it has one, with every tier shuffled, and falls back
+    /// to the single `announce` URL otherwise.
+    pub fn new(torrent: &Torrent) -> Self {
+        match &torrent.announce_list {
+            Some(list) if list.iter().any(|tier| !tier.is_empty()) => {
+                Self::from_tiers(list.clone())
+            }
+            _ if torrent.announce.is_empty() => Self::from_tiers(Vec::new()),
+            _ => Self::from_tiers(vec![vec![torrent.announce.clone()]]),
+        }
+    }
+
+    /// Uses the given tiers, skipping empty ones and shuffling the URLs within each tier.
+    pub fn from_tiers(tiers: Vec<Vec<String>>) -> Self {
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut thread_rng());
+                tier
+            })
+            .collect();
+        Self {
+            tiers,
+            udp_trackers: HashMap::new(),
+            http: http_client(),
+        }
+    }
+
+    /// Tries the trackers in tier order until one of them answers. The tracker that answered is
+    /// moved to the front of its tier so it is tried first next time.
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<Announce, Error> {
+        let mut last_error = Error::msg("Torrent has no trackers.");
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let announce = announce_to(
+                    &self.http,
+                    &mut self.udp_trackers,
+                    &tier[i],
+                    info_hash,
+                    request,
+                );
+                match announce.await {
+                    Ok(announce) => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(announce);
+                    }
+                    Err(e) => {
+                        eprintln!("Tracker {} failed: {}", tier[i], e);
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Client for HTTP trackers, which gives up on trackers that keep us waiting.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("HTTP client can be built")
+}
+
+/// Announces ourselves to the tracker at `url`.
+async fn announce_to(
+    http: &reqwest::Client,
+    udp_trackers: &mut HashMap<String, UdpTracker>,
+    url: &str,
+    info_hash: [u8; 20],
+    request: &TrackerRequest,
+) -> Result<Announce, Error> {
+    if url.starts_with("udp://") {
+        let tracker = match udp_trackers.entry(url.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(UdpTracker::new(url).await?),
+        };
+        return tracker.announce(info_hash, request).await;
+    }
+
+    let params = serde_urlencoded::to_string(request)?;
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let full_url = format!(
+        "{}{}info_hash={}&{}",
+        url,
+        separator,
+        urlencoding::encode_binary(&info_hash),
+        params
+    );
+
+    let response = http.get(full_url).send().await?.bytes().await?;
+    let response: TrackerResponse = serde_bencode::from_bytes(&response)?;
+    match response {
+        TrackerResponse::Error { failure_reason } => Err(Error::msg(format!(
+            "Peer request failed. Reason: {}",
+            failure_reason
+        ))),
+        TrackerResponse::Peers {
+            interval,
+            min_interval,
+            tracker_id,
+            mut peers,
+            peers6,
+            ..
+        } => {
+            peers.extend(peers6);
+            Ok(Announce {
+                interval: Duration::from_secs(interval),
+                min_interval: min_interval.map(Duration::from_secs),
+                tracker_id,
+                peers,
+            })
+        }
+    }
+}
+
+/// Announce state of a single torrent, kept for as long as we take part in its swarm.
+pub struct TrackerSession {
+    trackers: TrackerList,
+    info_hash: [u8; 20],
+    peer_id: String,
+    port: u32,
+    tracker_id: Option<String>,
+    started: bool,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    last_announce: Instant,
+}
+
+impl TrackerSession {
+    pub fn new(trackers: TrackerList, info_hash: [u8; 20], peer_id: &str) -> Self {
+        Self {
+            trackers,
+            info_hash,
+            peer_id: peer_id.to_string(),
+            port: 6881,
+            tracker_id: None,
+            started: false,
+            interval: Duration::ZERO,
+            min_interval: None,
+            last_announce: Instant::now(),
+        }
+    }
+
+    /// Announces our progress. Until an announce has succeeded, regular reannounces are sent as
+    /// `started`, while `completed` and `stopped` are skipped, as the trackers do not know us yet.
+    /// Later announces carry `event` (`None` for regular reannounces).
+    pub async fn announce(
+        &mut self,
+        event: Option<Event>,
+        uploaded: usize,
+        downloaded: usize,
+        left: usize,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let event = match event {
+            Some(Event::Completed | Event::Stopped) if !self.started => return Ok(Vec::new()),
+            None if !self.started => Some(Event::Started),
+            event => event,
+        };
+        let request = TrackerRequest {
+            peer_id: self.peer_id.clone(),
+            port: self.port,
+            uploaded,
+            downloaded,
+            left,
+            compact: 1,
+            event,
+            tracker_id: self.tracker_id.clone(),
+        };
+        let announce = self.trackers.announce(self.info_hash, &request).await?;
+        self.started = true;
+        self.last_announce = Instant::now();
+        self.interval = announce.interval;
+        self.min_interval = announce.min_interval;
+        if announce.tracker_id.is_some() {
+            self.tracker_id = announce.tracker_id;
+        }
+        Ok(announce.peers)
+    }
+
+    /// When the next regular announce is due.
+    pub fn next_announce(&self) -> Instant {
+        self.last_announce + self.interval.max(self.min_interval.unwrap_or_default())
+    }
+
+    /// The earliest moment the tracker allows us to announce again, for when we run out of peers
+    /// before the regular interval has passed.
+    pub fn earliest_announce(&self) -> Instant {
+        self.last_announce + self.min_interval.unwrap_or(self.interval)
+    }
+}
+
+/// Asks the torrent's trackers for peers.
+pub async fn get_peers(torrent: &Torrent, peer_id: &str) -> Result<Vec<SocketAddr>, Error> {
+    let request = TrackerRequest {
+        peer_id: peer_id.to_string(),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: torrent.info.length(),
+        compact: 1,
+        event: None,
+        tracker_id: None,
+    };
+    Ok(TrackerList::new(torrent)
+        .announce(torrent.info_hash, &request)
+        .await?
+        .peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(response: &[u8]) -> Vec<SocketAddr> {
+        match serde_bencode::from_bytes(response).unwrap() {
+            TrackerResponse::Peers {
+                mut peers, peers6, ..
+            } => {
+                peers.extend(peers6);
+                peers
+            }
+            TrackerResponse::Error { failure_reason } => panic!("{}", failure_reason),
+        }
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let response = [
+            &b"d8:intervali60e5:peers12:"[..],
+            &[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80],
+            b"6:peers618:",
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1],
+            b"e",
+        ]
+        .concat();
+        assert_eq!(
+            peers(&response),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap(),
+                "[::1]:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_dictionary_model_peers() {
+        let response = b"d8:intervali60e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa\
+            4:porti6881eed2:ip3:::14:porti80eed2:ip11:example.com4:porti1eeee";
+        assert_eq!(
+            peers(response),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:80".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_response_without_peers() {
+        assert!(peers(b"d8:intervali60ee").is_empty());
+    }
 }
@@ -0,0 +1,461 @@
+use anyhow::Error;
+use rand::{seq::SliceRandom, thread_rng};
+use serde_bencode::value::Value;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::{interval, timeout, Instant},
+};
+
+use crate::scrape::ScrapeStats;
+use crate::tracker::Event;
+
+/// Number of peers handed out when the announce does not say how many it wants.
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+/// Peers are forgotten when they have not reannounced within the interval plus this much.
+const EXPIRY_GRACE: Duration = Duration::from_secs(60);
+/// Requests whose head does not arrive in time, or grows beyond this size, are dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Everything a tracker needs to know about one announce, regardless of the protocol it came in.
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub address: SocketAddr,
+    pub left: u64,
+    pub event: Option<Event>,
+    pub numwant: Option<usize>,
+}
+
+/// The swarms a tracker keeps track of, keyed by info hash.
+pub struct Swarms {
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+    pub interval: Duration,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    /// Number of times a peer reported completing the download.
+    downloaded: u32,
+}
+
+struct SwarmPeer {
+    address: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+impl Swarms {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            swarms: Mutex::new(HashMap::new()),
+            interval,
+        }
+    }
+
+    /// Records the announce and returns up to `numwant` other peers of the swarm.
+    pub fn announce(&self, request: &AnnounceRequest) -> Vec<SocketAddr> {
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(request.info_hash).or_default();
+        if request.event == Some(Event::Stopped) {
+            swarm.peers.remove(&request.peer_id);
+            return Vec::new();
+        }
+        if request.event == Some(Event::Completed) {
+            swarm.downloaded += 1;
+        }
+        swarm.peers.insert(
+            request.peer_id,
+            SwarmPeer {
+                address: request.address,
+                left: request.left,
+                last_seen: Instant::now(),
+            },
+        );
+
+        let numwant = request.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+        let mut peers: Vec<SocketAddr> = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != request.peer_id)
+            .map(|(_, peer)| peer.address)
+            .collect();
+        peers.shuffle(&mut thread_rng());
+        peers.truncate(numwant);
+        peers
+    }
+
+    /// Statistics of the requested torrents, or of every known torrent if none are given.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+        let swarms = self.swarms.lock().unwrap();
+        let stats = |swarm: &Swarm| {
+            let complete = swarm.peers.values().filter(|p| p.left == 0).count() as u32;
+            ScrapeStats {
+                complete,
+                downloaded: swarm.downloaded,
+                incomplete: swarm.peers.len() as u32 - complete,
+            }
+        };
+        if info_hashes.is_empty() {
+            return swarms
+                .iter()
+                .map(|(info_hash, swarm)| (*info_hash, stats(swarm)))
+                .collect();
+        }
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                let stats = swarms.get(info_hash).map(stats).unwrap_or(ScrapeStats {
+                    complete: 0,
+                    downloaded: 0,
+                    incomplete: 0,
+                });
+                (*info_hash, stats)
+            })
+            .collect()
+    }
+
+    /// Forgets peers that missed their reannounce.
+    pub fn expire(&self) {
+        let deadline = self.interval + EXPIRY_GRACE;
+        let mut swarms = self.swarms.lock().unwrap();
+        for swarm in swarms.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| peer.last_seen.elapsed() <= deadline);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+
+    /// Periodically expires peers, for as long as the tracker runs.
+    pub async fn run_expiry(self: Arc<Self>) {
+        let mut ticker = interval(EXPIRY_GRACE);
+        loop {
+            ticker.tick().await;
+            self.expire();
+        }
+    }
+}
+
+/// Serves `/announce` and `/scrape` over HTTP.
+pub async fn serve_http(address: SocketAddr, swarms: Arc<Swarms>) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).await?;
+    println!("HTTP tracker listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let swarms = swarms.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, &swarms).await {
+                eprintln!("Tracker request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    swarms: &Swarms,
+) -> Result<(), Error> {
+    let target = timeout(REQUEST_TIMEOUT, read_request_target(&mut stream)).await??;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let params = parse_query(query);
+    let (status, body) = match path {
+        "/announce" => (
+            "200 OK",
+            match handle_announce(&params, peer.ip(), swarms) {
+                Ok(body) => body,
+                Err(e) => failure(&e.to_string()),
+            },
+        ),
+        "/scrape" => ("200 OK", handle_scrape(&params, swarms)),
+        _ => ("404 Not Found", failure("Not found.")),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the head of an HTTP request and returns the request target of a GET.
+async fn read_request_target(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // Skip the headers, none of them matter to us.
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).await?;
+        if read == 0 {
+            return Err(Error::msg("Request is incomplete or too large."));
+        }
+        if line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => Ok(target.to_string()),
+        _ => Err(Error::msg(format!(
+            "Unsupported request {:?}.",
+            request_line.trim_end()
+        ))),
+    }
+}
+
+/// Splits a query string into its parameters. Values are kept as raw bytes since `info_hash` and
+/// `peer_id` are binary. Keys may repeat, as in a scrape for several torrents.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                urlencoding::decode(key)
+                    .map(|k| k.into_owned())
+                    .unwrap_or_default(),
+                urlencoding::decode_binary(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_slice())
+}
+
+fn number_param<T: std::str::FromStr>(
+    params: &[(String, Vec<u8>)],
+    key: &str,
+) -> Result<Option<T>, Error> {
+    param(params, key)
+        .map(|v| {
+            std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or(Error::msg(format!("Invalid {}.", key)))
+        })
+        .transpose()
+}
+
+fn hash_param(value: &[u8], key: &str) -> Result<[u8; 20], Error> {
+    value
+        .try_into()
+        .map_err(|_| Error::msg(format!("Invalid {}.", key)))
+}
+
+/// Turns an IPv4-mapped IPv6 address back into the IPv4 address it stands for.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+fn handle_announce(
+    params: &[(String, Vec<u8>)],
+    ip: IpAddr,
+    swarms: &Swarms,
+) -> Result<Vec<u8>, Error> {
+    let missing = |key: &str| Error::msg(format!("Missing {}.", key));
+    let info_hash = hash_param(
+        param(params, "info_hash").ok_or_else(|| missing("info_hash"))?,
+        "info_hash",
+    )?;
+    let peer_id = hash_param(
+        param(params, "peer_id").ok_or_else(|| missing("peer_id"))?,
+        "peer_id",
+    )?;
+    let port: u16 = number_param(params, "port")?.ok_or_else(|| missing("port"))?;
+    let event = match param(params, "event") {
+        Some(b"started") => Some(Event::Started),
+        Some(b"completed") => Some(Event::Completed),
+        Some(b"stopped") => Some(Event::Stopped),
+        _ => None,
+    };
+    let request = AnnounceRequest {
+        info_hash,
+        peer_id,
+        // An IPv4 client connecting over a dual-stack socket shows up as an IPv4-mapped address.
+        address: SocketAddr::new(canonical_ip(ip), port),
+        left: number_param(params, "left")?.unwrap_or(0),
+        event,
+        numwant: number_param(params, "numwant")?,
+    };
+
+    let peers = swarms.announce(&request);
+    let mut compact = Vec::new();
+    let mut compact6 = Vec::new();
+    for peer in peers {
+        match peer {
+            SocketAddr::V4(address) => {
+                compact.extend_from_slice(&address.ip().octets());
+                compact.extend_from_slice(&address.port().to_be_bytes());
+            }
+            SocketAddr::V6(address) => {
+                compact6.extend_from_slice(&address.ip().octets());
+                compact6.extend_from_slice(&address.port().to_be_bytes());
+            }
+        }
+    }
+    let stats = swarms.scrape(&[info_hash])[0].1;
+    let response = dict(vec![
+        ("interval", Value::Int(swarms.interval.as_secs() as i64)),
+        ("complete", Value::Int(stats.complete.into())),
+        ("incomplete", Value::Int(stats.incomplete.into())),
+        ("peers", Value::Bytes(compact)),
+        ("peers6", Value::Bytes(compact6)),
+    ]);
+    Ok(serde_bencode::to_bytes(&response)?)
+}
+
+fn handle_scrape(params: &[(String, Vec<u8>)], swarms: &Swarms) -> Vec<u8> {
+    let info_hashes = match params
+        .iter()
+        .filter(|(k, _)| k == "info_hash")
+        .map(|(_, v)| hash_param(v, "info_hash"))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(info_hashes) => info_hashes,
+        Err(e) => return failure(&e.to_string()),
+    };
+    let files = swarms
+        .scrape(&info_hashes)
+        .into_iter()
+        .map(|(info_hash, stats)| {
+            (
+                info_hash.to_vec(),
+                dict(vec![
+                    ("complete", Value::Int(stats.complete.into())),
+                    ("downloaded", Value::Int(stats.downloaded.into())),
+                    ("incomplete", Value::Int(stats.incomplete.into())),
+                ]),
+            )
+        })
+        .collect();
+    serde_bencode::to_bytes(&dict(vec![("files", Value::Dict(files))]))
+        .expect("bencode values always serialize")
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    serde_bencode::to_bytes(&dict(vec![(
+        "failure reason",
+        Value::Bytes(reason.as_bytes().to_vec()),
+    )]))
+    .expect("bencode values always serialize")
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn announce(swarms: &Swarms, peer: u8, left: u64, event: Option<Event>) -> Vec<SocketAddr> {
+        swarms.announce(&AnnounceRequest {
+            info_hash: INFO_HASH,
+            peer_id: [peer; 20],
+            address: SocketAddr::from(([10, 0, 0, peer], 6881)),
+            left,
+            event,
+            numwant: None,
+        })
+    }
+
+    #[test]
+    fn announce_returns_the_other_peers() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        assert!(announce(&swarms, 1, 0, Some(Event::Started)).is_empty());
+        assert_eq!(
+            announce(&swarms, 2, 100, Some(Event::Started)),
+            vec![SocketAddr::from(([10, 0, 0, 1], 6881))]
+        );
+        assert_eq!(
+            announce(&swarms, 1, 0, None),
+            vec![SocketAddr::from(([10, 0, 0, 2], 6881))]
+        );
+    }
+
+    #[test]
+    fn announce_limits_peers_to_numwant() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        for peer in 1..=10 {
+            announce(&swarms, peer, 0, Some(Event::Started));
+        }
+        let peers = swarms.announce(&AnnounceRequest {
+            info_hash: INFO_HASH,
+            peer_id: [0; 20],
+            address: SocketAddr::from(([10, 0, 0, 0], 6881)),
+            left: 100,
+            event: Some(Event::Started),
+            numwant: Some(3),
+        });
+        assert_eq!(peers.len(), 3);
+    }
+
+    #[test]
+    fn scrape_counts_seeders_leechers_and_completions() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        announce(&swarms, 1, 0, Some(Event::Started));
+        announce(&swarms, 2, 100, Some(Event::Started));
+        announce(&swarms, 3, 100, Some(Event::Started));
+        announce(&swarms, 3, 0, Some(Event::Completed));
+        announce(&swarms, 2, 100, Some(Event::Stopped));
+
+        let stats = swarms.scrape(&[INFO_HASH, [2; 20]]);
+        assert_eq!(stats[0].0, INFO_HASH);
+        assert_eq!(
+            (
+                stats[0].1.complete,
+                stats[0].1.downloaded,
+                stats[0].1.incomplete
+            ),
+            (2, 1, 0)
+        );
+        // Unknown torrents are reported as empty.
+        assert_eq!(
+            (
+                stats[1].1.complete,
+                stats[1].1.downloaded,
+                stats[1].1.incomplete
+            ),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn canonical_ip_unmaps_ipv4() {
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(canonical_ip(mapped), "10.0.0.1".parse::<IpAddr>().unwrap());
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(canonical_ip(v6), v6);
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::Error;
+use bytes::{Buf, BufMut};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::scrape::ScrapeStats;
+use crate::tracker::{compact_peers_v4, compact_peers_v6, Announce, Event, TrackerRequest};
+
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+/// Number of info hashes that fit in a single scrape request.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+/// A connection ID can be used for announces and scrapes for one minute after it was received.
+pub const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Requests are retransmitted after 15 * 2 ^ n seconds. BEP 15 lets n go up to 8, which takes more
+/// than two hours for a tracker that is down, so we give up after 15 + 30 + 60 seconds instead.
+/// Connecting and the request itself share these retransmissions.
+const MAX_RETRANSMISSIONS: u32 = 2;
+
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+/// Client for the UDP tracker protocol (BEP 15).
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    /// Resolves the tracker of a `udp://host:port` URL and opens a socket towards it.
+    pub async fn new(url: &str) -> Result<Self, Error> {
+        let url = reqwest::Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or(Error::msg(format!("Tracker URL {} has no host.", url)))?;
+        let port = url
+            .port()
+            .ok_or(Error::msg(format!("Tracker URL {} has no port.", url)))?;
+        let address = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or(Error::msg(format!("Could not resolve {}.", host)))?;
+        let local: SocketAddr = if address.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+        })
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<Announce, Error> {
+        let peer_id: [u8; 20] = request.peer_id.as_bytes().try_into()?;
+        let response = self
+            .request(ACTION_ANNOUNCE, |packet| {
+                packet.put_slice(&info_hash);
+                packet.put_slice(&peer_id);
+                packet.put_u64(request.downloaded as u64);
+                packet.put_u64(request.left as u64);
+                packet.put_u64(request.uploaded as u64);
+                packet.put_u32(match request.event {
+                    None => 0,
+                    Some(Event::Completed) => 1,
+                    Some(Event::Started) => 2,
+                    Some(Event::Stopped) => 3,
+                });
+                // IP address: default
+                packet.put_u32(0);
+                packet.put_u32(rand::random());
+                // num_want: default
+                packet.put_i32(-1);
+                packet.put_u16(request.port as u16);
+            })
+            .await?;
+        let mut response = response.as_slice();
+        if response.remaining() < 12 {
+            return Err(Error::msg("Announce response is too short."));
+        }
+        let interval = response.get_u32();
+        let _leechers = response.get_u32();
+        let _seeders = response.get_u32();
+        Ok(Announce {
+            interval: Duration::from_secs(interval.into()),
+            min_interval: None,
+            tracker_id: None,
+            // Trackers reached over IPv6 answer with IPv6 peers.
+            peers: if self.socket.peer_addr()?.is_ipv6() {
+                compact_peers_v6(response)
+            } else {
+                compact_peers_v4(response)
+            },
+        })
+    }
+
+    /// Asks for the statistics of up to about 74 torrents at once.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, Error> {
+        let response = self
+            .request(ACTION_SCRAPE, |packet| {
+                for info_hash in info_hashes {
+                    packet.put_slice(info_hash);
+                }
+            })
+            .await?;
+        let mut response = response.as_slice();
+        if response.remaining() != 12 * info_hashes.len() {
+            return Err(Error::msg("Scrape response has an invalid length."));
+        }
+        Ok(info_hashes
+            .iter()
+            .map(|_| ScrapeStats {
+                complete: response.get_u32(),
+                downloaded: response.get_u32(),
+                incomplete: response.get_u32(),
+            })
+            .collect())
+    }
+
+    /// Sends a request with the given action, retransmitting it until the tracker answers, and
+    /// returns the response body following the action and transaction ID.
+    async fn request<F>(&mut self, action: u32, write_body: F) -> Result<Vec<u8>, Error>
+    where
+        F: Fn(&mut Vec<u8>),
+    {
+        let mut buf = vec![0; 64 * 1024];
+        for n in 0..=MAX_RETRANSMISSIONS {
+            let Some(connection_id) = self.connection_id(n).await? else {
+                continue;
+            };
+            let transaction_id: u32 = rand::random();
+            let mut packet = Vec::new();
+            packet.put_u64(connection_id);
+            packet.put_u32(action);
+            packet.put_u32(transaction_id);
+            write_body(&mut packet);
+
+            if let Some(len) = self.exchange(&packet, transaction_id, n, &mut buf).await? {
+                return Ok(check_response(&buf[..len], action)?.to_vec());
+            }
+        }
+        Err(Error::msg("Tracker did not respond."))
+    }
+
+    /// Returns the cached connection ID, or connects to the tracker again once it expired. This is
+    /// transmission `n` of a request, `None` means the tracker did not answer in time.
+    async fn connection_id(&mut self, n: u32) -> Result<Option<u64>, Error> {
+        if let Some((connection_id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(Some(connection_id));
+            }
+        }
+
+        let mut buf = [0; 16];
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16);
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(transaction_id);
+
+        let Some(len) = self.exchange(&packet, transaction_id, n, &mut buf).await? else {
+            return Ok(None);
+        };
+        let mut body = check_response(&buf[..len], ACTION_CONNECT)?;
+        if body.remaining() < 8 {
+            return Err(Error::msg("Connect response is too short."));
+        }
+        let connection_id = body.get_u64();
+        self.connection = Some((connection_id, Instant::now()));
+        Ok(Some(connection_id))
+    }
+
+    /// Sends `packet` and waits 15 * 2 ^ `n` seconds for the response carrying the same
+    /// transaction ID. Returns the length of the response, or `None` on timeout.
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        transaction_id: u32,
+        n: u32,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        self.socket.send(packet).await?;
+        let wait = Duration::from_secs(15 << n);
+        let receive = async {
+            loop {
+                let len = self.socket.recv(buf).await?;
+                // Drop stray responses to earlier transmissions.
+                if len >= 8 && buf[4..8] == transaction_id.to_be_bytes() {
+                    return Ok::<_, Error>(len);
+                }
+            }
+        };
+        match timeout(wait, receive).await {
+            Ok(len) => Ok(Some(len?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Checks the action of a response and turns error responses into errors. Returns the body of
+/// the response following the action and transaction ID.
+fn check_response(response: &[u8], action: u32) -> Result<&[u8], Error> {
+    let received_action = (&response[..4]).get_u32();
+    let body = &response[8..];
+    if received_action == ACTION_ERROR {
+        return Err(Error::msg(format!(
+            "Tracker returned an error: {}",
+            String::from_utf8_lossy(body)
+        )));
+    }
+    if received_action != action {
+        return Err(Error::msg(format!(
+            "Unexpected action {} in tracker response, expected {}.",
+            received_action, action
+        )));
+    }
+    Ok(body)
+}
@@ -0,0 +1,314 @@
+use anyhow::Error;
+use bytes::{Buf, BufMut};
+use sha1::{Digest, Sha1};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+
+use crate::tracker::Event;
+use crate::tracker_server::{canonical_ip, AnnounceRequest, Swarms};
+use crate::udp_tracker::{
+    ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, CONNECTION_ID_LIFETIME,
+    MAX_SCRAPE_HASHES, PROTOCOL_ID,
+};
+
+/// Serves the UDP tracker protocol (BEP 15) on top of the same swarms as the HTTP tracker.
+pub async fn serve_udp(address: SocketAddr, swarms: Arc<Swarms>) -> Result<(), Error> {
+    let socket = UdpSocket::bind(address).await?;
+    println!("UDP tracker listening on {}", socket.local_addr()?);
+    let connection_ids = ConnectionIds::new();
+    let mut buf = vec![0; 2048];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf).await?;
+        if let Some(response) = handle_packet(&buf[..len], peer, &connection_ids, &swarms) {
+            if let Err(e) = socket.send_to(&response, peer).await {
+                eprintln!("Could not answer {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+/// Issues connection IDs without having to remember them: an ID is a keyed hash of the client
+/// address and the current time window, so it can be recomputed when validating.
+struct ConnectionIds {
+    secret: [u8; 16],
+}
+
+impl ConnectionIds {
+    fn new() -> Self {
+        Self {
+            secret: rand::random(),
+        }
+    }
+
+    fn issue(&self, peer: SocketAddr) -> u64 {
+        self.for_window(peer, current_window())
+    }
+
+    /// IDs stay valid for the window they were issued in and the next one, so a client always
+    /// gets at least a full lifetime out of them.
+    fn is_valid(&self, peer: SocketAddr, connection_id: u64) -> bool {
+        let window = current_window();
+        connection_id == self.for_window(peer, window)
+            || connection_id == self.for_window(peer, window.wrapping_sub(1))
+    }
+
+    fn for_window(&self, peer: SocketAddr, window: u64) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(self.secret);
+        hasher.update(peer.to_string());
+        hasher.update(window.to_be_bytes());
+        let hash = hasher.finalize();
+        u64::from_be_bytes(hash[..8].try_into().unwrap())
+    }
+}
+
+fn current_window() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / CONNECTION_ID_LIFETIME.as_secs()
+}
+
+/// Answers a single request. Packets too short to carry a transaction ID are ignored.
+fn handle_packet(
+    packet: &[u8],
+    peer: SocketAddr,
+    connection_ids: &ConnectionIds,
+    swarms: &Swarms,
+) -> Option<Vec<u8>> {
+    if packet.len() < 16 {
+        return None;
+    }
+    let mut cur = packet;
+    let connection_id = cur.get_u64();
+    let action = cur.get_u32();
+    let transaction_id = cur.get_u32();
+
+    let mut response = Vec::new();
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return Some(error(transaction_id, "Invalid protocol ID."));
+        }
+        response.put_u32(ACTION_CONNECT);
+        response.put_u32(transaction_id);
+        response.put_u64(connection_ids.issue(peer));
+        return Some(response);
+    }
+    if !connection_ids.is_valid(peer, connection_id) {
+        return Some(error(transaction_id, "Invalid connection ID."));
+    }
+
+    match action {
+        ACTION_ANNOUNCE => {
+            if cur.remaining() < 82 {
+                return Some(error(transaction_id, "Announce request is too short."));
+            }
+            let mut info_hash = [0; 20];
+            cur.copy_to_slice(&mut info_hash);
+            let mut peer_id = [0; 20];
+            cur.copy_to_slice(&mut peer_id);
+            let _downloaded = cur.get_u64();
+            let left = cur.get_u64();
+            let _uploaded = cur.get_u64();
+            let event = match cur.get_u32() {
+                1 => Some(Event::Completed),
+                2 => Some(Event::Started),
+                3 => Some(Event::Stopped),
+                _ => None,
+            };
+            // The IP address field is ignored, peers are registered with their source address.
+            let _ip = cur.get_u32();
+            let _key = cur.get_u32();
+            let numwant = usize::try_from(cur.get_i32()).ok();
+            let port = cur.get_u16();
+
+            let address = SocketAddr::new(canonical_ip(peer.ip()), port);
+            let peers = swarms.announce(&AnnounceRequest {
+                info_hash,
+                peer_id,
+                address,
+                left,
+                event,
+                numwant,
+            });
+            let stats = swarms.scrape(&[info_hash])[0].1;
+            response.put_u32(ACTION_ANNOUNCE);
+            response.put_u32(transaction_id);
+            response.put_u32(swarms.interval.as_secs() as u32);
+            response.put_u32(stats.incomplete);
+            response.put_u32(stats.complete);
+            // Only peers of the address family the request came in with fit the response.
+            for peer in peers {
+                match (peer, address) {
+                    (SocketAddr::V4(peer), SocketAddr::V4(_)) => {
+                        response.put_slice(&peer.ip().octets());
+                        response.put_u16(peer.port());
+                    }
+                    (SocketAddr::V6(peer), SocketAddr::V6(_)) => {
+                        response.put_slice(&peer.ip().octets());
+                        response.put_u16(peer.port());
+                    }
+                    _ => {}
+                }
+            }
+            Some(response)
+        }
+        ACTION_SCRAPE => {
+            let info_hashes: Vec<[u8; 20]> = cur
+                .chunks_exact(20)
+                .take(MAX_SCRAPE_HASHES)
+                .map(|info_hash| info_hash.try_into().unwrap())
+                .collect();
+            if info_hashes.is_empty() {
+                return Some(error(transaction_id, "Scrape request has no info hashes."));
+            }
+            response.put_u32(ACTION_SCRAPE);
+            response.put_u32(transaction_id);
+            for (_, stats) in swarms.scrape(&info_hashes) {
+                response.put_u32(stats.complete);
+                response.put_u32(stats.downloaded);
+                response.put_u32(stats.incomplete);
+            }
+            Some(response)
+        }
+        _ => Some(error(transaction_id, "Unknown action.")),
+    }
+}
+
+fn error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut response = Vec::new();
+    response.put_u32(ACTION_ERROR);
+    response.put_u32(transaction_id);
+    response.put_slice(message.as_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn connect(peer: SocketAddr, connection_ids: &ConnectionIds, swarms: &Swarms) -> u64 {
+        let mut packet = Vec::new();
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(7);
+        let response = handle_packet(&packet, peer, connection_ids, swarms).unwrap();
+        let mut cur = &response[..];
+        assert_eq!(cur.get_u32(), ACTION_CONNECT);
+        assert_eq!(cur.get_u32(), 7);
+        cur.get_u64()
+    }
+
+    fn announce_packet(connection_id: u64, peer_id: u8, left: u64, port: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_ANNOUNCE);
+        packet.put_u32(8);
+        packet.put_slice(&INFO_HASH);
+        packet.put_slice(&[peer_id; 20]);
+        packet.put_u64(0);
+        packet.put_u64(left);
+        packet.put_u64(0);
+        packet.put_u32(2);
+        packet.put_u32(0);
+        packet.put_u32(0);
+        packet.put_i32(-1);
+        packet.put_u16(port);
+        packet
+    }
+
+    #[test]
+    fn announces_after_connecting() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        let connection_ids = ConnectionIds::new();
+        let seeder: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let leecher: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+
+        let id = connect(seeder, &connection_ids, &swarms);
+        handle_packet(
+            &announce_packet(id, 1, 0, 6881),
+            seeder,
+            &connection_ids,
+            &swarms,
+        )
+        .unwrap();
+        let id = connect(leecher, &connection_ids, &swarms);
+        let response = handle_packet(
+            &announce_packet(id, 2, 100, 6882),
+            leecher,
+            &connection_ids,
+            &swarms,
+        )
+        .unwrap();
+
+        let mut cur = &response[..];
+        assert_eq!(cur.get_u32(), ACTION_ANNOUNCE);
+        assert_eq!(cur.get_u32(), 8);
+        assert_eq!(cur.get_u32(), 1800);
+        assert_eq!((cur.get_u32(), cur.get_u32()), (1, 1));
+        // The seeder is registered with its source address and the port it announced.
+        assert_eq!(cur, &[10, 0, 0, 1, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn scrapes_after_connecting() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        let connection_ids = ConnectionIds::new();
+        let peer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let id = connect(peer, &connection_ids, &swarms);
+        handle_packet(
+            &announce_packet(id, 1, 0, 6881),
+            peer,
+            &connection_ids,
+            &swarms,
+        )
+        .unwrap();
+
+        let mut packet = Vec::new();
+        packet.put_u64(id);
+        packet.put_u32(ACTION_SCRAPE);
+        packet.put_u32(9);
+        packet.put_slice(&INFO_HASH);
+        let response = handle_packet(&packet, peer, &connection_ids, &swarms).unwrap();
+        let mut cur = &response[..];
+        assert_eq!(cur.get_u32(), ACTION_SCRAPE);
+        assert_eq!(cur.get_u32(), 9);
+        assert_eq!((cur.get_u32(), cur.get_u32(), cur.get_u32()), (1, 0, 0));
+        assert!(cur.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_connection_ids() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        let connection_ids = ConnectionIds::new();
+        let peer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let id = connect(peer, &connection_ids, &swarms);
+        let other: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+
+        let response = handle_packet(
+            &announce_packet(id, 1, 0, 6881),
+            other,
+            &connection_ids,
+            &swarms,
+        )
+        .unwrap();
+        let mut cur = &response[..];
+        assert_eq!(cur.get_u32(), ACTION_ERROR);
+        assert_eq!(cur.get_u32(), 8);
+        assert_eq!(cur, b"Invalid connection ID.");
+    }
+
+    #[test]
+    fn ignores_short_packets() {
+        let swarms = Swarms::new(Duration::from_secs(1800));
+        let peer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        assert!(handle_packet(&[0; 15], peer, &ConnectionIds::new(), &swarms).is_none());
+    }
+}